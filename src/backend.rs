@@ -0,0 +1,77 @@
+use std::{
+    error::Error,
+    sync::Arc,
+};
+use crate::color::Effect;
+
+#[cfg(target_os = "macos")]
+mod coregraphics;
+#[cfg(target_os = "linux")]
+mod wayland;
+#[cfg(target_os = "linux")]
+mod x11;
+#[cfg(test)]
+mod recording;
+
+#[cfg(test)]
+pub use recording::RecordingBackend;
+
+/// something that can put the nighttime effect on the display and take it off again,
+/// the effect is grayscale unless the config says otherwise so that's what it's called here
+pub trait DisplayBackend: Send + Sync {
+    /// short name to show in diagnostics
+    fn name(&self) -> &'static str;
+
+    /// check if the display currently has the whole effect on
+    fn is_grayscale(&self) -> bool;
+
+    /// turn the effect on or off
+    fn set_grayscale(&self, on: bool);
+
+    /// check if the effect can be put on part of the way,
+    /// without that fading in and out is a switch at the boundary
+    fn can_fade(&self) -> bool {
+        false
+    }
+
+    /// how much of the effect is on, from 0 for none to 1 for all of it
+    fn intensity(&self) -> f64 {
+        if self.is_grayscale() { 1.0 } else { 0.0 }
+    }
+
+    /// put the effect on part of the way, from 0 for none to 1 for all of it
+    fn set_intensity(&self, intensity: f64) {
+        self.set_grayscale(intensity >= 1.0);
+    }
+
+    /// check if the effect stays on the display once the process that put it there exits,
+    /// without that only the running app can keep it on
+    fn outlives_process(&self) -> bool {
+        true
+    }
+
+    /// put the display back when quitting the app,
+    /// `grayscale` is the state it should be left in
+    fn restore(&self, grayscale: bool) {
+        self.set_grayscale(grayscale);
+    }
+}
+
+/// pick the backend for the platform we're running on, set up to put `effect` on the display
+pub fn select(effect: Effect) -> Result<Arc<dyn DisplayBackend>, Box<dyn Error>> {
+    #[cfg(target_os = "macos")]
+    return Ok(Arc::new(coregraphics::CoreGraphics::new(effect)));
+
+    #[cfg(target_os = "linux")]
+    return if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        Ok(Arc::new(wayland::Wayland::connect(effect)?))
+    } else {
+        Ok(Arc::new(x11::X11::connect(effect)?))
+    };
+
+    #[cfg(not(any(target_os = "macos", target_os = "linux")))]
+    {
+        let _ = effect;
+        return Err("no display backend available for this platform".into());
+    }
+}
@@ -0,0 +1,117 @@
+use std::sync::Mutex;
+use log::warn;
+use crate::color::Effect;
+use super::DisplayBackend;
+
+type CGDirectDisplayID = u32;
+type CGError = i32;
+
+const MAX_DISPLAYS: usize = 16;
+const TABLE_SIZE: usize = 256;
+
+#[link(name = "ApplicationServices", kind = "framework")]
+extern {
+    fn CGDisplayUsesForceToGray() -> bool;
+    fn CGDisplayForceToGray(forceToGray: bool);
+    fn CGGetOnlineDisplayList(maxDisplays: u32, onlineDisplays: *mut CGDirectDisplayID, displayCount: *mut u32) -> CGError;
+    fn CGSetDisplayTransferByTable(
+        display: CGDirectDisplayID,
+        tableSize: u32,
+        redTable: *const f32,
+        greenTable: *const f32,
+        blueTable: *const f32,
+    ) -> CGError;
+    fn CGDisplayRestoreColorSyncSettings();
+}
+
+/// macos backend using the same switch as the accessibility grayscale option,
+/// the ops that keep to their channel go through the gamma tables of every display
+///
+/// the grayscale switch is all or nothing, so this can only fade when the effect doesn't have it,
+/// and there's nothing for mixing channels any other way
+pub struct CoreGraphics {
+    effect: Effect,
+    /// how much of the gamma part we last put on, macos can't tell us
+    gamma: Mutex<f64>,
+}
+
+impl CoreGraphics {
+    pub fn new(effect: Effect) -> Self {
+        if effect.pipeline.iter().any(|op| !op.is_per_channel()) {
+            warn!("coregraphics can't mix colour channels, desaturating and matrices in the pipeline are left out");
+        }
+        Self { effect, gamma: Mutex::new(0.0) }
+    }
+
+    fn set_gamma(&self, intensity: f64) {
+        if !self.effect.has_gamma() {
+            return;
+        }
+        *self.gamma.lock().unwrap() = intensity;
+        if intensity <= 0.0 {
+            // gives back whatever the colour profile had, not just a linear curve
+            return unsafe { CGDisplayRestoreColorSyncSettings() };
+        }
+        let transform = self.effect.gamma(intensity);
+        let table = |channel| -> Vec<f32> {
+            (0..TABLE_SIZE).map(|i| transform.channel(channel, i as f64 / (TABLE_SIZE - 1) as f64) as f32).collect()
+        };
+        let (red, green, blue) = (table(0), table(1), table(2));
+        let mut displays = [0; MAX_DISPLAYS];
+        let mut count = 0;
+        if unsafe { CGGetOnlineDisplayList(MAX_DISPLAYS as u32, displays.as_mut_ptr(), &mut count) } != 0 {
+            return warn!("can't list the displays to set their gamma");
+        }
+        for display in &displays[..count as usize] {
+            let err = unsafe {
+                CGSetDisplayTransferByTable(
+                    *display, TABLE_SIZE as u32, red.as_ptr(), green.as_ptr(), blue.as_ptr(),
+                )
+            };
+            if err != 0 {
+                warn!("can't set gamma on display {}: error {}", display, err);
+            }
+        }
+    }
+}
+
+impl DisplayBackend for CoreGraphics {
+    fn name(&self) -> &'static str {
+        "coregraphics"
+    }
+
+    fn is_grayscale(&self) -> bool {
+        self.intensity() >= 1.0
+    }
+
+    fn set_grayscale(&self, on: bool) {
+        self.set_intensity(if on { 1.0 } else { 0.0 });
+    }
+
+    fn can_fade(&self) -> bool {
+        !self.effect.grayscale && self.effect.has_gamma()
+    }
+
+    /// macos puts back the colour profile's gamma tables when we exit, only the grayscale switch stays
+    fn outlives_process(&self) -> bool {
+        !self.effect.has_gamma()
+    }
+
+    fn intensity(&self) -> f64 {
+        if self.effect.grayscale {
+            if unsafe { CGDisplayUsesForceToGray() } { 1.0 } else { 0.0 }
+        } else {
+            *self.gamma.lock().unwrap()
+        }
+    }
+
+    fn set_intensity(&self, intensity: f64) {
+        if self.effect.grayscale {
+            let on = intensity >= 1.0;
+            unsafe { CGDisplayForceToGray(on) };
+            self.set_gamma(if on { 1.0 } else { 0.0 });
+        } else {
+            self.set_gamma(intensity);
+        }
+    }
+}
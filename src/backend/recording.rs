@@ -0,0 +1,77 @@
+use std::sync::Mutex;
+use super::DisplayBackend;
+
+/// backend for tests that remembers every switch instead of touching a display
+#[derive(Default)]
+pub struct RecordingBackend {
+    grayscale: Mutex<bool>,
+    switches: Mutex<Vec<bool>>,
+    /// `None` for a backend that can only switch between on and off
+    intensity: Option<Mutex<f64>>,
+    intensities: Mutex<Vec<f64>>,
+}
+
+impl RecordingBackend {
+    pub fn new(grayscale: bool) -> Self {
+        Self { grayscale: Mutex::new(grayscale), ..Self::default() }
+    }
+
+    /// backend that can fade, it records every change of intensity as well
+    pub fn fading(intensity: f64) -> Self {
+        Self {
+            grayscale: Mutex::new(intensity >= 1.0),
+            intensity: Some(Mutex::new(intensity)),
+            ..Self::default()
+        }
+    }
+
+    /// return the switches recorded since the last call
+    pub fn take_switches(&self) -> Vec<bool> {
+        self.switches.lock().unwrap().drain(..).collect()
+    }
+
+    /// return the intensities set since the last call
+    pub fn take_intensities(&self) -> Vec<f64> {
+        self.intensities.lock().unwrap().drain(..).collect()
+    }
+}
+
+impl DisplayBackend for RecordingBackend {
+    fn name(&self) -> &'static str {
+        "recording"
+    }
+
+    fn is_grayscale(&self) -> bool {
+        *self.grayscale.lock().unwrap()
+    }
+
+    fn set_grayscale(&self, on: bool) {
+        *self.grayscale.lock().unwrap() = on;
+        self.switches.lock().unwrap().push(on);
+        if let Some(intensity) = &self.intensity {
+            *intensity.lock().unwrap() = if on { 1.0 } else { 0.0 };
+        }
+    }
+
+    fn can_fade(&self) -> bool {
+        self.intensity.is_some()
+    }
+
+    fn intensity(&self) -> f64 {
+        match &self.intensity {
+            Some(intensity) => *intensity.lock().unwrap(),
+            None => if self.is_grayscale() { 1.0 } else { 0.0 },
+        }
+    }
+
+    fn set_intensity(&self, intensity: f64) {
+        match &self.intensity {
+            Some(current) => {
+                *current.lock().unwrap() = intensity;
+                *self.grayscale.lock().unwrap() = intensity >= 1.0;
+                self.intensities.lock().unwrap().push(intensity);
+            },
+            None => self.set_grayscale(intensity >= 1.0),
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use std::{
+    cell::Cell,
+    error::Error,
+    fs::File,
+    io::{Seek, SeekFrom, Write},
+    os::unix::io::AsRawFd,
+    rc::Rc,
+    sync::{Mutex, mpsc::{self, Receiver, Sender}},
+    thread,
+};
+use wayland_client::{Display, EventQueue, GlobalManager, Main, protocol::wl_output::WlOutput};
+use wayland_protocols::wlr::unstable::gamma_control::v1::client::{
+    zwlr_gamma_control_manager_v1::ZwlrGammaControlManagerV1,
+    zwlr_gamma_control_v1::{self, ZwlrGammaControlV1},
+};
+use log::warn;
+use crate::color::{Effect, Transform};
+use super::DisplayBackend;
+
+enum Request {
+    /// put tables for this on every output we control
+    Apply(Transform),
+    /// give up the gamma controls so the compositor puts back its own tables
+    Release,
+}
+
+/// wayland backend for wlroots compositors using `zwlr_gamma_control_manager_v1`
+///
+/// wayland objects can't leave the thread that dispatches their events,
+/// so the connection lives on its own thread for the life of the process
+/// and holds on to the gamma controls, that way no other client can
+/// take them over while we're running.
+///
+/// gamma tables have one curve per channel and can't mix channels,
+/// which is what desaturating needs, so only the ops that keep to
+/// their channel end up on the screen and the rest is left out.
+pub struct Wayland {
+    requests: Mutex<(Sender<Request>, Receiver<()>)>,
+    effect: Effect,
+    intensity: Mutex<f64>,
+}
+
+impl Wayland {
+    /// connect to the compositor in `$WAYLAND_DISPLAY` and take the gamma control of every output
+    pub fn connect(effect: Effect) -> Result<Self, Box<dyn Error>> {
+        // there'd be nothing on screen to schedule
+        if !effect.has_gamma() {
+            return Err(
+                "desaturating isn't available on wayland, wlr-gamma-control can't mix colour channels, \
+                 the effect needs a temperature or brightness too".into()
+            );
+        }
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let (request_tx, request_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let outputs = match Outputs::connect() {
+                Ok(outputs) => {
+                    ready_tx.send(Ok(())).unwrap();
+                    outputs
+                },
+                Err(err) => return ready_tx.send(Err(err.to_string())).unwrap(),
+            };
+            outputs.serve(request_rx, done_tx);
+        });
+        ready_rx.recv()??;
+        if !effect.is_per_channel() {
+            warn!(
+                "desaturating isn't available on wayland, wlr-gamma-control can't mix colour channels, \
+                 only the warmth and dimming of the effect will show"
+            );
+        }
+        Ok(Self {
+            requests: Mutex::new((request_tx, done_rx)),
+            effect,
+            intensity: Mutex::new(0.0),
+        })
+    }
+
+    fn send(&self, request: Request) {
+        let requests = self.requests.lock().unwrap();
+        // the connection thread only stops when the compositor goes away
+        if requests.0.send(request).is_ok() {
+            requests.1.recv().ok();
+        }
+    }
+}
+
+impl DisplayBackend for Wayland {
+    fn name(&self) -> &'static str {
+        "wayland"
+    }
+
+    fn is_grayscale(&self) -> bool {
+        self.intensity() >= 1.0
+    }
+
+    fn set_grayscale(&self, on: bool) {
+        self.set_intensity(if on { 1.0 } else { 0.0 });
+    }
+
+    fn can_fade(&self) -> bool {
+        true
+    }
+
+    /// none of it is on screen when gamma tables have nothing to do
+    fn intensity(&self) -> f64 {
+        if self.effect.has_gamma() {
+            *self.intensity.lock().unwrap()
+        } else {
+            0.0
+        }
+    }
+
+    fn set_intensity(&self, intensity: f64) {
+        *self.intensity.lock().unwrap() = intensity;
+        if self.effect.has_gamma() {
+            self.send(Request::Apply(self.effect.gamma(intensity)));
+        }
+    }
+
+    fn outlives_process(&self) -> bool {
+        false
+    }
+
+    /// the compositor resets gamma when we let go of the controls,
+    /// which also happens when the process exits, so there's no way
+    /// to leave the effect on after quitting
+    fn restore(&self, _grayscale: bool) {
+        self.send(Request::Release);
+    }
+}
+
+/// gamma control of one output and the size of its tables,
+/// the size is zero until the compositor tells us
+struct Output {
+    control: Main<ZwlrGammaControlV1>,
+    size: Rc<Cell<u32>>,
+    failed: Rc<Cell<bool>>,
+}
+
+struct Outputs {
+    display: Display,
+    queue: EventQueue,
+    outputs: Vec<Output>,
+}
+
+impl Outputs {
+    fn connect() -> Result<Self, Box<dyn Error>> {
+        let display = Display::connect_to_env()?;
+        let mut queue = display.create_event_queue();
+        let attached = (*display).clone().attach(queue.token());
+        let globals = GlobalManager::new(&attached);
+        queue.sync_roundtrip(&mut (), |_, _, _| {})?;
+
+        let manager = globals.instantiate_exact::<ZwlrGammaControlManagerV1>(1)
+            .map_err(|_| "compositor doesn't support wlr-gamma-control")?;
+        let registry = attached.get_registry();
+        let outputs: Vec<Output> = globals.list().into_iter()
+            .filter(|(_, interface, _)| interface == "wl_output")
+            .map(|(id, _, _)| {
+                let output = registry.bind::<WlOutput>(1, id);
+                let control = manager.get_gamma_control(&output);
+                let size = Rc::new(Cell::new(0));
+                let failed = Rc::new(Cell::new(false));
+                let (size_ref, failed_ref) = (size.clone(), failed.clone());
+                control.quick_assign(move |_, event, _| match event {
+                    zwlr_gamma_control_v1::Event::GammaSize { size } => size_ref.set(size),
+                    zwlr_gamma_control_v1::Event::Failed => failed_ref.set(true),
+                    _ => {},
+                });
+                Output { control, size, failed }
+            })
+            .collect();
+        queue.sync_roundtrip(&mut (), |_, _, _| {})?;
+
+        if outputs.iter().all(|output| output.failed.get()) {
+            return Err("another program is already controlling gamma on every output".into());
+        }
+        Ok(Self { display, queue, outputs })
+    }
+
+    fn serve(mut self, requests: Receiver<Request>, done: Sender<()>) {
+        for request in requests {
+            // the tables have to stay open until they're sent
+            let mut tables = Vec::new();
+            match request {
+                Request::Apply(transform) => {
+                    for output in self.outputs.iter().filter(|output| !output.failed.get()) {
+                        match set_gamma(&output.control, &transform.ramps(output.size.get())) {
+                            Ok(table) => tables.push(table),
+                            Err(err) => warn!("can't set gamma: {}", err),
+                        }
+                    }
+                },
+                Request::Release => {
+                    for output in self.outputs.drain(..) {
+                        output.control.destroy();
+                    }
+                },
+            }
+            self.display.flush().ok();
+            self.queue.dispatch_pending(&mut (), |_, _, _| {}).ok();
+            done.send(()).ok();
+        }
+    }
+}
+
+/// hand the tables for red, green and blue over to the compositor,
+/// it expects them one after the other in a file
+fn set_gamma(control: &ZwlrGammaControlV1, ramps: &[Vec<u16>; 3]) -> Result<File, Box<dyn Error>> {
+    let mut file: File = tempfile::tempfile()?;
+    for ramp in ramps {
+        let bytes: Vec<u8> = ramp.iter().flat_map(|value| value.to_ne_bytes().to_vec()).collect();
+        file.write_all(&bytes)?;
+    }
+    file.seek(SeekFrom::Start(0))?;
+    control.set_gamma(file.as_raw_fd());
+    Ok(file)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::color::ramp;
+
+    /// a backend whose connection thread is already gone
+    fn disconnected(effect: Effect) -> Wayland {
+        let (request_tx, _) = mpsc::channel();
+        let (_, done_rx) = mpsc::channel();
+        Wayland { requests: Mutex::new((request_tx, done_rx)), effect, intensity: Mutex::new(0.0) }
+    }
+
+    #[test]
+    fn only_reports_what_is_on_screen() {
+        let grayscale = disconnected(Effect::default());
+        assert!(!grayscale.outlives_process());
+        grayscale.set_grayscale(true);
+        assert!(!grayscale.is_grayscale());
+        assert_eq!(grayscale.intensity(), 0.0);
+
+        let warm = disconnected(Effect { temperature: Some(3400), ..Effect::default() });
+        warm.set_intensity(0.5);
+        assert_eq!(warm.intensity(), 0.5);
+        warm.set_grayscale(true);
+        assert!(warm.is_grayscale());
+    }
+
+    #[test]
+    fn refuses_an_effect_it_cant_show() {
+        let err = Wayland::connect(Effect::default()).err().unwrap();
+        assert!(err.to_string().contains("desaturating isn't available on wayland"), "{}", err);
+    }
+
+    #[test]
+    fn linear() {
+        assert_eq!(ramp(3, 1.0, 0.0), vec![0, 0x7fff, 0xffff]);
+        assert_eq!(ramp(256, 1.0, 0.0)[255], 0xffff);
+        assert!(ramp(0, 1.0, 0.0).is_empty());
+    }
+
+    /// needs a wlroots compositor, e.g. `WLR_BACKENDS=headless sway` with
+    /// `WAYLAND_DISPLAY` pointing at it, then `cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn headless_compositor() {
+        let backend = Wayland::connect(Effect { temperature: Some(3400), brightness: Some(0.7), ..Effect::default() }).unwrap();
+        assert!(!backend.is_grayscale());
+        backend.set_grayscale(true);
+        assert!(backend.is_grayscale());
+        backend.set_grayscale(false);
+        assert!(!backend.is_grayscale());
+        backend.set_intensity(0.5);
+        assert_eq!(backend.intensity(), 0.5);
+        backend.restore(false);
+    }
+}
@@ -0,0 +1,312 @@
+use std::error::Error;
+use x11rb::{
+    connection::{Connection, RequestConnection},
+    protocol::{
+        randr::{self, ConnectionExt as _},
+        xproto::{Atom, AtomEnum, ConnectionExt as _, PropMode},
+    },
+    rust_connection::RustConnection,
+};
+use log::warn;
+use crate::color::{Effect, Matrix};
+use super::DisplayBackend;
+
+/// x11 backend that puts the effect on every connected output through the colour
+/// transformation matrix that randr exposes as the `CTM` output property,
+/// the pipeline of ops goes into that one matrix except for inverting, a matrix
+/// can't add to the channels so it's left out there with a warning
+///
+/// outputs without one get what gamma tables can do instead, that's warming and
+/// dimming but not desaturating
+pub struct X11 {
+    conn: RustConnection,
+    ctm: Atom,
+    /// outputs we can change, with how they were before we touched them
+    outputs: Vec<Output>,
+    effect: Effect,
+}
+
+enum Output {
+    /// changed through its `CTM` property, with the matrix it had
+    Matrix { output: randr::Output, original: Vec<u8> },
+    /// changed through the gamma tables of the crtc that drives it, with the ones it had
+    Gamma { crtc: randr::Crtc, original: [Vec<u16>; 3] },
+}
+
+impl X11 {
+    /// connect to the display in `$DISPLAY` and find the outputs we can change
+    pub fn connect(effect: Effect) -> Result<Self, Box<dyn Error>> {
+        let (conn, screen) = RustConnection::connect(None)?;
+        if conn.extension_information(randr::X11_EXTENSION_NAME)?.is_none() {
+            return Err("x server doesn't support randr".into());
+        }
+        let root = conn.setup().roots[screen].root;
+        let ctm = conn.intern_atom(false, b"CTM")?.reply()?.atom;
+
+        let resources = conn.randr_get_screen_resources_current(root)?.reply()?;
+        let mut outputs = Vec::new();
+        for output in resources.outputs {
+            let info = conn.randr_get_output_info(output, resources.config_timestamp)?.reply()?;
+            if info.connection != randr::Connection::CONNECTED || info.crtc == x11rb::NONE {
+                continue;
+            }
+            let name = String::from_utf8_lossy(&info.name);
+            let properties = conn.randr_list_output_properties(output)?.reply()?.atoms;
+            if properties.contains(&ctm) {
+                let original = read_ctm(&conn, ctm, output)?;
+                outputs.push(Output::Matrix { output, original });
+                continue;
+            }
+            // gamma ramps can't mix channels so there's no way to desaturate without a matrix
+            if !effect.has_gamma() {
+                warn!("output {} has no colour transformation matrix, it will stay in colour", name);
+                continue;
+            }
+            // mirrored outputs share a crtc, its tables only need saving once
+            if outputs.iter().any(|known| matches!(known, Output::Gamma { crtc, .. } if *crtc == info.crtc)) {
+                continue;
+            }
+            let gamma = conn.randr_get_crtc_gamma(info.crtc)?.reply()?;
+            if gamma.red.is_empty() {
+                warn!("output {} has neither a colour transformation matrix nor gamma tables, it's left alone", name);
+                continue;
+            }
+            if !effect.is_per_channel() {
+                warn!("output {} has no colour transformation matrix, it only gets warmer or dimmer through its gamma tables", name);
+            }
+            outputs.push(Output::Gamma { crtc: info.crtc, original: [gamma.red, gamma.green, gamma.blue] });
+        }
+        if outputs.is_empty() {
+            return Err(
+                "none of the connected outputs has a colour transformation matrix to desaturate with, \
+                 gamma tables can only make them warmer or dimmer".into()
+            );
+        }
+        if effect.has_offset() && outputs.iter().any(|output| matches!(output, Output::Matrix { .. })) {
+            warn!("the colour transformation matrix can't add to a channel, inverting is left out");
+        }
+        Ok(Self { conn, ctm, outputs, effect })
+    }
+
+    fn write(&self, output: randr::Output, data: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.conn.randr_change_output_property(
+            output, self.ctm, AtomEnum::INTEGER.into(), 32, PropMode::REPLACE,
+            (data.len() / 4) as u32, data,
+        )?.check()?;
+        Ok(())
+    }
+
+    fn write_gamma(&self, crtc: randr::Crtc, ramps: &[Vec<u16>; 3]) -> Result<(), Box<dyn Error>> {
+        self.conn.randr_set_crtc_gamma(crtc, &ramps[0], &ramps[1], &ramps[2])?.check()?;
+        Ok(())
+    }
+
+    /// how far `output` is into the effect, anything else on it counts as none of it
+    fn read_intensity(&self, output: &Output) -> Option<f64> {
+        match output {
+            Output::Matrix { output, .. } => read_ctm(&self.conn, self.ctm, *output).ok()
+                .and_then(|data| decode_ctm(&data))
+                .and_then(|matrix| self.effect.intensity_of(&matrix)),
+            Output::Gamma { crtc, .. } => {
+                let gamma = self.conn.randr_get_crtc_gamma(*crtc).ok()?.reply().ok()?;
+                self.effect.intensity_of_ramps(&[gamma.red, gamma.green, gamma.blue])
+            },
+        }
+    }
+
+    fn set(&self, intensity: f64) {
+        let matrix = encode_ctm(&self.effect.matrix(intensity));
+        let gamma = self.effect.gamma(intensity);
+        for output in &self.outputs {
+            match output {
+                Output::Matrix { output, .. } => if let Err(err) = self.write(*output, &matrix) {
+                    warn!("can't set colour transformation matrix on output {}: {}", output, err);
+                },
+                Output::Gamma { crtc, original } => {
+                    if let Err(err) = self.write_gamma(*crtc, &gamma.ramps(original[0].len() as u32)) {
+                        warn!("can't set gamma tables on crtc {}: {}", crtc, err);
+                    }
+                },
+            }
+        }
+    }
+}
+
+impl DisplayBackend for X11 {
+    fn name(&self) -> &'static str {
+        "x11"
+    }
+
+    fn is_grayscale(&self) -> bool {
+        self.outputs.iter().all(|output| matches!(self.read_intensity(output), Some(intensity) if intensity > 1.0 - 1e-3))
+    }
+
+    fn set_grayscale(&self, on: bool) {
+        self.set(if on { 1.0 } else { 0.0 });
+    }
+
+    fn can_fade(&self) -> bool {
+        true
+    }
+
+    /// read back how far the first output is into the effect
+    fn intensity(&self) -> f64 {
+        self.outputs.first().and_then(|output| self.read_intensity(output)).unwrap_or(0.0)
+    }
+
+    fn set_intensity(&self, intensity: f64) {
+        self.set(intensity);
+    }
+
+    fn restore(&self, grayscale: bool) {
+        if grayscale {
+            return self.set_grayscale(true);
+        }
+        // put back whatever the outputs had before, it might not be the identity
+        for output in &self.outputs {
+            match output {
+                Output::Matrix { output, original } => if let Err(err) = self.write(*output, original) {
+                    warn!("can't restore colour transformation matrix on output {}: {}", output, err);
+                },
+                Output::Gamma { crtc, original } => if let Err(err) = self.write_gamma(*crtc, original) {
+                    warn!("can't restore gamma tables on crtc {}: {}", crtc, err);
+                },
+            }
+        }
+    }
+}
+
+fn read_ctm(conn: &RustConnection, ctm: Atom, output: randr::Output) -> Result<Vec<u8>, Box<dyn Error>> {
+    let reply = conn.randr_get_output_property(output, ctm, AtomEnum::ANY, 0, 18, false, false)?.reply()?;
+    Ok(reply.data)
+}
+
+/// encode a matrix the way the kernel expects it in `drm_color_ctm`,
+/// as 64-bit sign-magnitude S31.32 fixed point numbers that the
+/// x server passes along as pairs of 32-bit property items
+fn encode_ctm(matrix: &Matrix) -> Vec<u8> {
+    matrix.iter().flat_map(|&value| {
+        let magnitude = (value.abs() * (1u64 << 32) as f64).round() as u64 & !(1 << 63);
+        let sign = if value < 0.0 { 1 << 63 } else { 0 };
+        (magnitude | sign).to_ne_bytes().to_vec()
+    }).collect()
+}
+
+fn decode_ctm(data: &[u8]) -> Option<Matrix> {
+    if data.len() != 9 * 8 {
+        return None;
+    }
+    let mut matrix = [0.0; 9];
+    for (value, bytes) in matrix.iter_mut().zip(data.chunks(8)) {
+        let mut raw = [0; 8];
+        raw.copy_from_slice(bytes);
+        let raw = u64::from_ne_bytes(raw);
+        let magnitude = (raw & !(1 << 63)) as f64 / (1u64 << 32) as f64;
+        *value = if raw & (1 << 63) != 0 { -magnitude } else { magnitude };
+    }
+    Some(matrix)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::color::{approx_eq, Op, GRAYSCALE, IDENTITY};
+
+    #[test]
+    fn ctm_identity() {
+        let data = encode_ctm(&IDENTITY);
+        assert_eq!(data.len(), 72);
+        assert_eq!(u64::from_ne_bytes([data[0], data[1], data[2], data[3], data[4], data[5], data[6], data[7]]), 1 << 32);
+        assert_eq!(&data[8..16], &[0; 8]);
+    }
+
+    #[test]
+    fn ctm_negative() {
+        let mut matrix = IDENTITY;
+        matrix[1] = -0.5;
+        let data = encode_ctm(&matrix);
+        assert_eq!(u64::from_ne_bytes([data[8], data[9], data[10], data[11], data[12], data[13], data[14], data[15]]), (1 << 63) | (1 << 31));
+        assert_eq!(decode_ctm(&data), Some(matrix));
+    }
+
+    #[test]
+    fn ctm_roundtrip() {
+        let decoded = decode_ctm(&encode_ctm(&GRAYSCALE)).unwrap();
+        assert!(approx_eq(&decoded, &GRAYSCALE));
+    }
+
+    #[test]
+    fn desaturate_halfway() {
+        let grayscale = Effect::default();
+        assert!(approx_eq(&grayscale.matrix(0.0), &IDENTITY));
+        assert!(approx_eq(&grayscale.matrix(1.0), &GRAYSCALE));
+        let half = grayscale.matrix(0.5);
+        assert!((half[0] - (1.0 + 0.2126) / 2.0).abs() < 1e-9);
+        assert!((half[1] - 0.7152 / 2.0).abs() < 1e-9);
+        // every row still adds up to one so white stays white
+        for row in half.chunks(3) {
+            assert!((row.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn invert_never_reaches_the_ctm() {
+        let pipelines = vec![
+            vec![Op::Invert],
+            vec![Op::Desaturate(0.5), Op::Invert],
+            vec![Op::Tint([1.0, 0.5, 0.0]), Op::Invert, Op::Dim(0.5)],
+            vec![Op::Invert, Op::Invert],
+        ];
+        for pipeline in pipelines {
+            let effect = Effect { grayscale: false, pipeline, ..Effect::default() };
+            for step in 0..=10 {
+                let matrix = decode_ctm(&encode_ctm(&effect.matrix(step as f64 / 10.0))).unwrap();
+                for channel in 0..3 {
+                    assert!(matrix[channel * 4] >= 0.0, "{:?} at {}: {:?}", effect.pipeline, step, matrix);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ctm_wrong_size() {
+        assert_eq!(decode_ctm(&[0; 18]), None);
+    }
+
+    /// needs an x server, e.g. `xvfb-run cargo test -- --ignored`
+    ///
+    /// xvfb doesn't have a colour pipeline, so we create the `CTM` property
+    /// ourselves the way a kms driver would before connecting the backend
+    #[test]
+    #[ignore]
+    fn xvfb() {
+        let (conn, screen) = RustConnection::connect(None).unwrap();
+        let root = conn.setup().roots[screen].root;
+        let ctm = conn.intern_atom(false, b"CTM").unwrap().reply().unwrap().atom;
+        let resources = conn.randr_get_screen_resources_current(root).unwrap().reply().unwrap();
+        for output in resources.outputs {
+            let data = encode_ctm(&IDENTITY);
+            conn.randr_change_output_property(output, ctm, AtomEnum::INTEGER.into(), 32, PropMode::REPLACE, 18, &data)
+                .unwrap().check().unwrap();
+        }
+
+        let backend = X11::connect(Effect::default()).unwrap();
+        assert!(!backend.is_grayscale());
+        backend.set_grayscale(true);
+        assert!(backend.is_grayscale());
+        backend.set_grayscale(false);
+        assert!(!backend.is_grayscale());
+        backend.set_intensity(0.25);
+        assert!((backend.intensity() - 0.25).abs() < 1e-6);
+        backend.set_grayscale(true);
+        backend.restore(false);
+        assert!(!backend.is_grayscale());
+
+        let warm = X11::connect(Effect { grayscale: false, temperature: Some(3400), ..Effect::default() }).unwrap();
+        warm.set_intensity(0.5);
+        assert!((warm.intensity() - 0.5).abs() < 1e-6);
+        warm.set_grayscale(true);
+        assert!(warm.is_grayscale());
+        warm.restore(false);
+    }
+}
@@ -0,0 +1,316 @@
+use std::{
+    error::Error,
+    io,
+    path::Path,
+    process,
+};
+use chrono::{DateTime, Duration, Local, NaiveTime, TimeZone};
+use crate::backend;
+use crate::config::{self, Config, ConfigError};
+use crate::instance::InstanceLock;
+use crate::ipc::{Client, Request, Response};
+use crate::schedule::WeeklySchedule;
+use crate::scheduler::{Lasting, Snooze, State};
+use crate::timerange::parse_offset;
+
+pub const USAGE: &str = "\
+usage: goodnight [command]
+
+without a command it starts the app and keeps the display on schedule
+
+commands:
+    daemon          keep the display on schedule without a tray, until stopped with a signal
+    status          show whether it's nighttime and whether the effect is on
+    on [how long]   put the effect on the display until the schedule switches it, or for a while
+                    like 15m or 1h30m, until a time like 01:30 or forever, until it's put back
+    off [how long]  take the effect off the display, for as long as with on
+    toggle          switch the effect on or off until the schedule switches it
+    schedule        put the display back on the schedule after on, off or snooze
+    snooze <when>   take the effect off for a while, like 15m or 1h30m, or until a time like 01:30
+    next            show when nighttime starts or ends next
+    check-config    check the config file for errors without changing it
+    help            show this";
+
+/// what the binary was asked to do on the command line
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    /// start the app, which is what happens without a command
+    Run,
+    /// the same without a tray, for a service manager or a desktop that doesn't have one
+    Daemon,
+    Status,
+    On(Lasting),
+    Off(Lasting),
+    Toggle,
+    /// end an override or a snooze
+    Schedule,
+    Snooze(Snooze),
+    Next,
+    CheckConfig,
+    Help,
+}
+
+/// work out the action from the arguments after the name of the binary
+pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Action, String> {
+    let mut args = args.into_iter();
+    let action = match args.next().as_deref() {
+        None => Action::Run,
+        Some("daemon") => Action::Daemon,
+        Some("status") => Action::Status,
+        Some("on") => Action::On(lasting(args.next().as_deref())?),
+        Some("off") => Action::Off(lasting(args.next().as_deref())?),
+        Some("toggle") => Action::Toggle,
+        Some("schedule") => Action::Schedule,
+        Some("snooze") => Action::Snooze(snooze(args.next().as_deref())?),
+        Some("next") => Action::Next,
+        Some("check-config") => Action::CheckConfig,
+        Some("help") | Some("-h") | Some("--help") => Action::Help,
+        Some(other) => return Err(format!("unknown command {:?}", other)),
+    };
+    if let Some(extra) = args.next() {
+        return Err(format!("unexpected argument {:?}", extra));
+    }
+    Ok(action)
+}
+
+/// how long to snooze from something like `15m`, `1h30m` or `01:30`
+fn snooze(when: Option<&str>) -> Result<Snooze, String> {
+    let when = when.ok_or("snooze needs how long, like 15m or 1h, or until when, like 01:30")?;
+    how_long(when).ok_or_else(|| format!("can't snooze for {:?}, it should look like 15m, 1h30m or 01:30", when))
+}
+
+/// how long to keep the effect on or off, until the schedule switches it without anything
+fn lasting(when: Option<&str>) -> Result<Lasting, String> {
+    match when {
+        None => Ok(Lasting::NextBoundary),
+        Some("forever") => Ok(Lasting::Forever),
+        Some(when) => match how_long(when) {
+            Some(Snooze::For(duration)) => Ok(Lasting::Minutes(duration.as_secs().div_ceil(60))),
+            Some(Snooze::Until(time)) => Ok(Lasting::Until(time)),
+            None => Err(format!("can't keep it like that for {:?}, it should look like 15m, 1h30m, 01:30 or forever", when)),
+        },
+    }
+}
+
+fn how_long(when: &str) -> Option<Snooze> {
+    if let Ok(time) = NaiveTime::parse_from_str(when, "%H:%M") {
+        return Some(Snooze::Until(time));
+    }
+    parse_offset(&format!("+{}", when))
+        .and_then(|duration| duration.to_std().ok())
+        .filter(|duration| duration.as_secs() > 0)
+        .map(Snooze::For)
+}
+
+/// do one of the commands and exit, the app itself isn't started for these
+///
+/// if the app is running the commands go to it through the socket at `socket_path`,
+/// otherwise the effect is switched on the display directly, unless the lock at `lock_path`
+/// says it's running after all and would fight us over the display, or the effect
+/// would go away again as soon as we exit
+pub fn run(action: Action, config_path: &Path, socket_path: &Path, lock_path: &Path) -> Result<(), Box<dyn Error>> {
+    match Client::connect(socket_path) {
+        Ok(client) => if let Some(result) = forward(action, client) {
+            return result;
+        },
+        Err(err) => if action != Action::CheckConfig && action != Action::Next {
+            if let Err(running) = InstanceLock::check(lock_path) {
+                return Err(format!("{} but can't be reached on {}: {}", running, socket_path.display(), err).into());
+            }
+        },
+    }
+    // none of the commands should change the file, so it's not `config::load`
+    let config = match config::check(config_path) {
+        Ok(config) => config,
+        // same as the app on its first run
+        Err(ConfigError::Io(err)) if err.kind() == io::ErrorKind::NotFound && action != Action::CheckConfig => {
+            Config::default()
+        },
+        Err(err) => {
+            println!("error in config file {}: {}", config_path.display(), err);
+            process::exit(1);
+        },
+    };
+    let nighttime = config.schedule();
+    let now = Local::now();
+    match action {
+        Action::CheckConfig => {
+            println!("config file {} is fine", config_path.display());
+            println!("tonight: {}", nighttime.tonight(&now));
+        },
+        Action::Next => println!("{}", next(&nighttime, &now)),
+        Action::Status => {
+            let backend = backend::select(config.effect.clone())?;
+            println!("nighttime: {}", if nighttime.includes(&now) { "yes" } else { "no" });
+            if backend.outlives_process() {
+                println!("effect: {}", effect(backend.intensity()));
+            } else {
+                // it went away with whoever put it on
+                println!("effect: off, the app isn't running");
+            }
+            println!("tonight: {}", nighttime.tonight(&now));
+            println!("{}", next(&nighttime, &now));
+        },
+        Action::On(Lasting::Minutes(_) | Lasting::Until(_)) | Action::Off(Lasting::Minutes(_) | Lasting::Until(_)) => {
+            println!("the app isn't running, so there's nothing to switch it back after a while");
+            process::exit(1);
+        },
+        Action::On(_) | Action::Off(_) | Action::Toggle => {
+            let backend = backend::select(config.effect.clone())?;
+            if !backend.outlives_process() {
+                println!("the app isn't running, and on {} the effect only lasts as long as the app does", backend.name());
+                process::exit(1);
+            }
+            let on = match action {
+                Action::On(_) => true,
+                Action::Off(_) => false,
+                _ => !backend.is_grayscale(),
+            };
+            backend.set_grayscale(on);
+            println!("effect: {}", if on { "on" } else { "off" });
+        },
+        Action::Snooze(_) | Action::Schedule => {
+            println!("the app isn't running, so there's no schedule to go back to");
+            process::exit(1);
+        },
+        Action::Run | Action::Daemon | Action::Help => unreachable!("{:?} isn't a command", action),
+    }
+    Ok(())
+}
+
+/// do `action` through the running app, `None` for the ones that don't need it
+fn forward(action: Action, mut client: Client) -> Option<Result<(), Box<dyn Error>>> {
+    let (on, lasting) = match action {
+        Action::Status => return Some(state(&mut client).map(|state| print_state(&state))),
+        Action::On(lasting) => (Some(true), lasting),
+        Action::Off(lasting) => (Some(false), lasting),
+        Action::Toggle => match state(&mut client) {
+            Ok(state) => (Some(state.intensity <= 0.0), Lasting::NextBoundary),
+            Err(err) => return Some(Err(err)),
+        },
+        Action::Schedule => (None, Lasting::NextBoundary),
+        Action::Snooze(snooze) => {
+            let request = match snooze {
+                Snooze::For(duration) => Request::Snooze { minutes: duration.as_secs().div_ceil(60) },
+                Snooze::Until(until) => Request::SnoozeUntil { until },
+            };
+            return Some(match client.request(&request) {
+                Ok(Response::Ok) => state(&mut client).map(|state| print_state(&state)),
+                response => Err(unexpected(response)),
+            });
+        },
+        _ => return None,
+    };
+    Some(match client.request(&Request::SetOverride { on, lasting }) {
+        Ok(Response::Ok) => state(&mut client).map(|state| print_state(&state)),
+        response => Err(unexpected(response)),
+    })
+}
+
+fn state(client: &mut Client) -> Result<State, Box<dyn Error>> {
+    match client.request(&Request::GetState) {
+        Ok(Response::State(state)) => Ok(state),
+        response => Err(unexpected(response)),
+    }
+}
+
+fn unexpected(response: io::Result<Response>) -> Box<dyn Error> {
+    match response {
+        Ok(Response::Error { message }) => format!("the running app says: {}", message).into(),
+        Ok(response) => format!("unexpected answer from the running app: {:?}", response).into(),
+        Err(err) => format!("can't talk to the running app: {}", err).into(),
+    }
+}
+
+fn print_state(state: &State) {
+    println!("nighttime: {}", if state.nighttime { "yes" } else { "no" });
+    println!("effect: {}", effect(state.intensity));
+    if let Some(overridden) = &state.overridden {
+        println!("{}", overridden);
+    }
+    if let Some(boundary) = state.next_boundary {
+        let what = if state.nighttime { "ends" } else { "starts" };
+        println!("nighttime {} at {}", what, boundary.format("%a %H:%M"));
+    }
+}
+
+fn effect(intensity: f64) -> String {
+    match intensity {
+        intensity if intensity <= 0.0 => "off".to_owned(),
+        intensity if intensity >= 1.0 => "on".to_owned(),
+        intensity => format!("{:.0}%", intensity * 100.0),
+    }
+}
+
+/// say when nighttime starts or ends after `now` and how long that is from now
+fn next<Tz: TimeZone>(nighttime: &WeeklySchedule, now: &DateTime<Tz>) -> String
+    where Tz::Offset: std::fmt::Display,
+{
+    let boundary = match nighttime.next_boundary_after(now) {
+        Some(boundary) => boundary,
+        None => return "nighttime doesn't start or end this week".to_owned(),
+    };
+    let what = if nighttime.includes(now) { "ends" } else { "starts" };
+    format!("nighttime {} at {} in {}", what, boundary.format("%a %H:%M"), duration(boundary - now.clone()))
+}
+
+/// a duration like `3h 05m`, rounded down to the minute
+fn duration(duration: Duration) -> String {
+    let minutes = duration.num_minutes();
+    format!("{}h {:02}m", minutes / 60, minutes % 60)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::Utc;
+    use crate::schedule::Weekdays;
+    use crate::timerange::TimeRange;
+
+    fn parse_args(args: &[&str]) -> Result<Action, String> {
+        parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse_args(&[]), Ok(Action::Run));
+        assert_eq!(parse_args(&["daemon"]), Ok(Action::Daemon));
+        assert_eq!(parse_args(&["status"]), Ok(Action::Status));
+        assert_eq!(parse_args(&["on"]), Ok(Action::On(Lasting::NextBoundary)));
+        assert_eq!(parse_args(&["off"]), Ok(Action::Off(Lasting::NextBoundary)));
+        assert_eq!(parse_args(&["toggle"]), Ok(Action::Toggle));
+        assert_eq!(parse_args(&["schedule"]), Ok(Action::Schedule));
+        assert_eq!(parse_args(&["next"]), Ok(Action::Next));
+        assert_eq!(parse_args(&["check-config"]), Ok(Action::CheckConfig));
+        assert_eq!(parse_args(&["--help"]), Ok(Action::Help));
+        assert!(parse_args(&["sleep"]).is_err());
+        assert!(parse_args(&["on", "now"]).is_err());
+        assert!(parse_args(&["on", "1h", "more"]).is_err());
+
+        assert_eq!(parse_args(&["on", "forever"]), Ok(Action::On(Lasting::Forever)));
+        assert_eq!(parse_args(&["off", "1h30m"]), Ok(Action::Off(Lasting::Minutes(90))));
+        assert_eq!(parse_args(&["on", "01:30"]), Ok(Action::On(Lasting::Until(NaiveTime::from_hms_opt(1, 30, 0).unwrap()))));
+
+        let minutes = |minutes: u64| Ok(Action::Snooze(Snooze::For(std::time::Duration::from_secs(minutes * 60))));
+        assert_eq!(parse_args(&["snooze", "15m"]), minutes(15));
+        assert_eq!(parse_args(&["snooze", "1h"]), minutes(60));
+        assert_eq!(parse_args(&["snooze", "1h30m"]), minutes(90));
+        assert_eq!(parse_args(&["snooze", "01:30"]), Ok(Action::Snooze(Snooze::Until(NaiveTime::from_hms_opt(1, 30, 0).unwrap()))));
+        assert!(parse_args(&["snooze"]).is_err());
+        assert!(parse_args(&["snooze", "0m"]).is_err());
+        assert!(parse_args(&["snooze", "later"]).is_err());
+    }
+
+    #[test]
+    fn says_what_happens_next() {
+        let nighttime = WeeklySchedule::new(TimeRange::from_hmhm(23, 0, 7, 0).into(), Weekdays::default(), None);
+        // a monday
+        let evening = Utc.with_ymd_and_hms(2021, 1, 4, 19, 55, 30).unwrap();
+        assert_eq!(next(&nighttime, &evening), "nighttime starts at Mon 23:00 in 3h 04m");
+        let night = Utc.with_ymd_and_hms(2021, 1, 5, 1, 0, 0).unwrap();
+        assert_eq!(next(&nighttime, &night), "nighttime ends at Tue 07:00 in 6h 00m");
+
+        let never = WeeklySchedule::new(Vec::<TimeRange>::new().into(), Weekdays::default(), None);
+        assert_eq!(next(&never, &evening), "nighttime doesn't start or end this week");
+    }
+}
@@ -0,0 +1,84 @@
+use std::{
+    sync::mpsc::{Receiver, RecvTimeoutError},
+    time::Duration,
+};
+#[cfg(test)]
+use std::sync::{Arc, Mutex, mpsc::TryRecvError};
+use chrono::{DateTime, Local, TimeZone};
+
+/// where the scheduler gets the time from and how it waits for it to pass
+pub trait Clock {
+    type Tz: TimeZone;
+
+    /// current wall clock time
+    fn now(&self) -> DateTime<Self::Tz>;
+
+    /// block the current thread until something arrives on `receiver`
+    /// or until `timeout` has passed, whichever is first
+    fn wait<T>(&self, receiver: &Receiver<T>, timeout: Duration) -> Result<T, RecvTimeoutError>;
+}
+
+/// the real clock in the local timezone
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    type Tz = Local;
+
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+
+    /// the timeout runs on a monotonic clock that may stop while the machine
+    /// is suspended, so the wall clock can be further along when we wake up
+    fn wait<T>(&self, receiver: &Receiver<T>, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        receiver.recv_timeout(timeout)
+    }
+}
+
+/// clock for tests that only moves when told to,
+/// clones share the same time so the test can keep a handle to it
+#[cfg(test)]
+#[derive(Clone)]
+pub struct FakeClock<Tz: TimeZone> {
+    now: Arc<Mutex<DateTime<Tz>>>,
+}
+
+#[cfg(test)]
+impl<Tz: TimeZone> FakeClock<Tz> {
+    pub fn new(now: DateTime<Tz>) -> Self {
+        Self { now: Arc::new(Mutex::new(now)) }
+    }
+
+    /// move the time forward without anyone noticing, like a laptop waking up
+    pub fn advance(&self, duration: chrono::Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now = now.clone() + duration;
+    }
+
+    /// switch to another timezone at the same instant, like when travelling
+    pub fn set_timezone(&self, tz: &Tz) {
+        let mut now = self.now.lock().unwrap();
+        *now = now.with_timezone(tz);
+    }
+}
+
+#[cfg(test)]
+impl<Tz: TimeZone> Clock for FakeClock<Tz> {
+    type Tz = Tz;
+
+    fn now(&self) -> DateTime<Tz> {
+        self.now.lock().unwrap().clone()
+    }
+
+    /// waiting returns immediately, if nothing was sent the whole timeout has passed
+    fn wait<T>(&self, receiver: &Receiver<T>, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        match receiver.try_recv() {
+            Ok(message) => Ok(message),
+            Err(TryRecvError::Disconnected) => Err(RecvTimeoutError::Disconnected),
+            Err(TryRecvError::Empty) => {
+                self.advance(chrono::Duration::from_std(timeout).unwrap());
+                Err(RecvTimeoutError::Timeout)
+            },
+        }
+    }
+}
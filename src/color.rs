@@ -0,0 +1,668 @@
+use serde::{Serialize, Deserialize, Deserializer};
+use crate::config::number;
+
+/// row-major colour transformation matrix that's applied to linear rgb
+pub type Matrix = [f64; 9];
+
+pub const IDENTITY: Matrix = [
+    1.0, 0.0, 0.0,
+    0.0, 1.0, 0.0,
+    0.0, 0.0, 1.0,
+];
+
+/// every channel gets the same rec. 709 luminance
+pub const GRAYSCALE: Matrix = [
+    0.2126, 0.7152, 0.0722,
+    0.2126, 0.7152, 0.0722,
+    0.2126, 0.7152, 0.0722,
+];
+
+/// colour temperature of daylight that screens are usually set to, it leaves colours as they are
+pub const NEUTRAL_KELVIN: u32 = 6500;
+
+/// one step of the nighttime effect, they're applied one after the other
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Op {
+    /// take away this much of the colour, 1 is grayscale
+    Desaturate(#[serde(deserialize_with = "floor")] f64),
+    /// multiply red, green and blue by these
+    Tint(#[serde(deserialize_with = "finite")] [f64; 3]),
+    /// make white look like a black body glowing at this many kelvin
+    Temperature(u32),
+    /// turn dark into light and the other way around
+    Invert,
+    /// scale the brightness down to this, never below the floor of the effect
+    Dim(#[serde(deserialize_with = "floor")] f64),
+    /// any other mix of the channels, row by row
+    Matrix(#[serde(deserialize_with = "finite")] Matrix),
+}
+
+impl Op {
+    fn transform(&self, brightness_floor: f64) -> Transform {
+        match self {
+            Op::Desaturate(amount) => Transform::linear(lerp(&IDENTITY, &GRAYSCALE, *amount)),
+            Op::Tint(scale) => Transform::linear(diagonal(*scale)),
+            Op::Temperature(kelvin) => Transform::linear(diagonal(temperature(*kelvin))),
+            Op::Invert => Transform { matrix: diagonal([-1.0; 3]), offset: [1.0; 3] },
+            Op::Dim(brightness) => Transform::linear(diagonal([dim(*brightness, brightness_floor); 3])),
+            Op::Matrix(matrix) => Transform::linear(*matrix),
+        }
+    }
+
+    /// check if every channel only depends on itself, which is all gamma tables can do
+    pub fn is_per_channel(&self) -> bool {
+        !matches!(self, Op::Desaturate(_) | Op::Matrix(_))
+    }
+
+    /// check if it adds to the channels, which a matrix on its own can't do
+    pub fn has_offset(&self) -> bool {
+        matches!(self, Op::Invert)
+    }
+}
+
+/// what happens to the display at nighttime
+///
+/// the first few fields are shorthands for the common ops, they come first
+/// in that order and whatever is in `pipeline` is applied after them
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Effect {
+    /// desaturate the display
+    #[serde(default = "default_grayscale")]
+    pub grayscale: bool,
+    /// make the display warmer by lowering its colour temperature to this many kelvin
+    #[serde(default, skip_serializing_if = "Option::is_none", deserialize_with = "kelvin")]
+    pub temperature: Option<u32>,
+    /// dim the display to this much of its brightness, from 0 to 1
+    #[serde(default, skip_serializing_if = "Option::is_none", deserialize_with = "fraction")]
+    pub brightness: Option<f64>,
+    /// dimming never goes darker than this, so the screen stays readable
+    #[serde(default = "default_brightness_floor", deserialize_with = "floor")]
+    pub brightness_floor: f64,
+    /// more ops after the ones above
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pipeline: Vec<Op>,
+}
+
+fn default_grayscale() -> bool {
+    true
+}
+
+fn default_brightness_floor() -> f64 {
+    0.3
+}
+
+fn kelvin<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    let kelvin = number(deserializer, 1000.0..=40000.0, "colour temperature has to be between 1000 and 40000 kelvin")?;
+    Ok(Some(kelvin as u32))
+}
+
+fn fraction<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    floor(deserializer).map(Some)
+}
+
+fn floor<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    number(deserializer, 0.0..=1.0, "has to be between 0 and 1")
+}
+
+/// a number that isn't infinite or `.nan`, for the entries of tints and matrices
+struct Finite(f64);
+
+impl<'de> Deserialize<'de> for Finite {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        number(deserializer, f64::MIN..=f64::MAX, "has to be a finite number").map(Finite)
+    }
+}
+
+fn finite<'de, D: Deserializer<'de>, const N: usize>(deserializer: D) -> Result<[f64; N], D::Error>
+    where [Finite; N]: Deserialize<'de>,
+{
+    Ok(<[Finite; N]>::deserialize(deserializer)?.map(|Finite(value)| value))
+}
+
+impl Default for Effect {
+    fn default() -> Self {
+        Self {
+            grayscale: true,
+            temperature: None,
+            brightness: None,
+            brightness_floor: default_brightness_floor(),
+            pipeline: Vec::new(),
+        }
+    }
+}
+
+impl Effect {
+    /// every op in the order they're applied
+    pub fn ops(&self) -> Vec<Op> {
+        let mut ops = Vec::new();
+        if self.grayscale {
+            ops.push(Op::Desaturate(1.0));
+        }
+        ops.extend(self.temperature.map(Op::Temperature));
+        ops.extend(self.brightness.map(Op::Dim));
+        ops.extend(self.pipeline.iter().cloned());
+        ops
+    }
+
+    /// the whole effect at `intensity`, from 0 for leaving the display alone to 1 for all of it
+    #[allow(unused)]
+    pub fn transform(&self, intensity: f64) -> Transform {
+        self.reduce(intensity, |_| true)
+    }
+
+    /// the part of the effect a matrix can do on its own, for backends that only have one,
+    /// ops that add to the channels like inverting are left out rather than leaving
+    /// their matrix without what it adds, inverting without it turns everything black
+    pub fn matrix(&self, intensity: f64) -> Matrix {
+        self.reduce(intensity, |op| !op.has_offset()).matrix
+    }
+
+    /// check if some of the effect can't be done with a matrix
+    pub fn has_offset(&self) -> bool {
+        self.ops().iter().any(Op::has_offset)
+    }
+
+    /// the part of the effect that gamma tables can do, the ops that
+    /// mix channels like desaturating are left out
+    pub fn gamma(&self, intensity: f64) -> Transform {
+        self.reduce(intensity, Op::is_per_channel)
+    }
+
+    /// check if there's anything for gamma tables to do
+    pub fn has_gamma(&self) -> bool {
+        self.ops().iter().any(Op::is_per_channel)
+    }
+
+    /// check if all of the effect can be done with gamma tables
+    pub fn is_per_channel(&self) -> bool {
+        self.ops().iter().all(Op::is_per_channel)
+    }
+
+    /// how far `matrix` is into the effect, if it's somewhere between none and all of it
+    pub fn intensity_of(&self, matrix: &Matrix) -> Option<f64> {
+        let full = self.matrix(1.0);
+        // read it off the entry that changes the most, the others have to agree
+        let (index, change) = full.iter().zip(IDENTITY.iter())
+            .map(|(full, identity)| full - identity)
+            .enumerate()
+            .max_by(|a, b| a.1.abs().total_cmp(&b.1.abs()))?;
+        let intensity = if change.abs() < 1e-6 { 0.0 } else { (matrix[index] - IDENTITY[index]) / change };
+        if (0.0..=1.0 + 1e-6).contains(&intensity) && approx_eq(matrix, &self.matrix(intensity)) {
+            Some(intensity.min(1.0))
+        } else {
+            None
+        }
+    }
+
+    /// how far gamma tables are into the gamma part of the effect, like `intensity_of`
+    pub fn intensity_of_ramps(&self, ramps: &[Vec<u16>; 3]) -> Option<f64> {
+        let size = ramps[0].len() as u32;
+        let none = Transform::IDENTITY.ramps(size);
+        let full = self.gamma(1.0).ramps(size);
+        let (channel, index, change) = (0..3)
+            .flat_map(|channel| (0..size as usize).map(move |index| (channel, index)))
+            .map(|(channel, index)| (channel, index, full[channel][index] as f64 - none[channel][index] as f64))
+            .max_by(|a, b| a.2.abs().total_cmp(&b.2.abs()))?;
+        let intensity = if change.abs() < 1.0 {
+            0.0
+        } else {
+            (ramps[channel][index] as f64 - none[channel][index] as f64) / change
+        };
+        // the entries are rounded so they only have to be within a step or two
+        let expected = self.gamma(intensity).ramps(size);
+        let close = ramps.iter().zip(expected.iter()).all(|(ramp, expected)| {
+            ramp.len() == expected.len()
+                && ramp.iter().zip(expected.iter()).all(|(a, b)| (*a as i32 - *b as i32).abs() <= 2)
+        });
+        if (-1e-6..=1.0 + 1e-6).contains(&intensity) && close {
+            Some(intensity.clamp(0.0, 1.0))
+        } else {
+            None
+        }
+    }
+
+    fn reduce(&self, intensity: f64, keep: impl Fn(&Op) -> bool) -> Transform {
+        let full = self.ops().iter()
+            .filter(|op| keep(op))
+            .fold(Transform::IDENTITY, |transform, op| transform.then(&op.transform(self.brightness_floor)));
+        Transform::IDENTITY.lerp(&full, intensity)
+    }
+}
+
+fn dim(brightness: f64, floor: f64) -> f64 {
+    brightness.max(floor).clamp(0.0, 1.0)
+}
+
+/// affine colour transformation, every colour goes to `matrix * rgb + offset`,
+/// it's what a pipeline of ops reduces to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    pub matrix: Matrix,
+    pub offset: [f64; 3],
+}
+
+impl Transform {
+    pub const IDENTITY: Transform = Transform { matrix: IDENTITY, offset: [0.0; 3] };
+
+    pub fn linear(matrix: Matrix) -> Self {
+        Self { matrix, offset: [0.0; 3] }
+    }
+
+    /// the transformation that applies this one first and then `next`
+    pub fn then(&self, next: &Transform) -> Transform {
+        let mut offset = next.offset;
+        for (row, offset) in offset.iter_mut().enumerate() {
+            *offset += (0..3).map(|i| next.matrix[row * 3 + i] * self.offset[i]).sum::<f64>();
+        }
+        Transform { matrix: multiply(&next.matrix, &self.matrix), offset }
+    }
+
+    /// blend from this at 0 to `other` at 1
+    pub fn lerp(&self, other: &Transform, amount: f64) -> Transform {
+        let amount = amount.clamp(0.0, 1.0);
+        let mut offset = self.offset;
+        for (value, target) in offset.iter_mut().zip(other.offset.iter()) {
+            *value += (target - *value) * amount;
+        }
+        Transform { matrix: lerp(&self.matrix, &other.matrix, amount), offset }
+    }
+
+    /// check if it needs anything more than a matrix
+    #[allow(unused)]
+    pub fn has_offset(&self) -> bool {
+        self.offset.iter().any(|offset| offset.abs() > 1e-6)
+    }
+
+    /// how much red, green and blue get scaled, ignoring any mixing
+    #[allow(unused)]
+    pub fn scale(&self) -> [f64; 3] {
+        [self.matrix[0], self.matrix[4], self.matrix[8]]
+    }
+
+    /// where `value` of `channel` ends up, ignoring any mixing, clamped to what a display can show
+    #[cfg_attr(not(target_os = "macos"), allow(unused))]
+    pub fn channel(&self, channel: usize, value: f64) -> f64 {
+        (self.matrix[channel * 4] * value + self.offset[channel]).clamp(0.0, 1.0)
+    }
+
+    /// gamma tables for red, green and blue with `size` entries each,
+    /// only the per channel part of the transformation makes it into them
+    pub fn ramps(&self, size: u32) -> [Vec<u16>; 3] {
+        let channel = |channel: usize| ramp(size, self.matrix[channel * 4], self.offset[channel]);
+        [channel(0), channel(1), channel(2)]
+    }
+}
+
+/// how much to scale red, green and blue to make white look like a black body
+/// glowing at `kelvin`, the neutral temperature leaves everything as it is
+///
+/// this is tanner helland's fit of the black body colours, which is plenty
+/// for tinting a screen and doesn't need a table
+pub fn temperature(kelvin: u32) -> [f64; 3] {
+    let white = black_body(NEUTRAL_KELVIN);
+    let colour = black_body(kelvin);
+    let mut scale = [0.0; 3];
+    for ((scale, colour), white) in scale.iter_mut().zip(colour.iter()).zip(white.iter()) {
+        *scale = (colour / white).clamp(0.0, 1.0);
+    }
+    scale
+}
+
+fn black_body(kelvin: u32) -> [f64; 3] {
+    let t = kelvin.clamp(1000, 40000) as f64 / 100.0;
+    let red = if t <= 66.0 { 255.0 } else { 329.698_727_446 * (t - 60.0).powf(-0.133_204_759_2) };
+    let green = if t <= 66.0 {
+        99.470_802_586_1 * t.ln() - 161.119_568_166_1
+    } else {
+        288.122_169_528_3 * (t - 60.0).powf(-0.075_514_849_2)
+    };
+    let blue = if t >= 66.0 {
+        255.0
+    } else if t <= 19.0 {
+        0.0
+    } else {
+        138.517_731_223_1 * (t - 10.0).ln() - 305.044_792_730_7
+    };
+    let mut colour = [red, green, blue];
+    for channel in colour.iter_mut() {
+        *channel = channel.clamp(0.0, 255.0) / 255.0;
+    }
+    colour
+}
+
+pub fn diagonal(scale: [f64; 3]) -> Matrix {
+    [
+        scale[0], 0.0, 0.0,
+        0.0, scale[1], 0.0,
+        0.0, 0.0, scale[2],
+    ]
+}
+
+/// the matrix that applies `b` first and then `a`
+pub fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut product = [0.0; 9];
+    for row in 0..3 {
+        for column in 0..3 {
+            product[row * 3 + column] = (0..3).map(|i| a[row * 3 + i] * b[i * 3 + column]).sum();
+        }
+    }
+    product
+}
+
+/// blend from `a` at 0 to `b` at 1
+pub fn lerp(a: &Matrix, b: &Matrix, amount: f64) -> Matrix {
+    let amount = amount.clamp(0.0, 1.0);
+    let mut blend = *a;
+    for (value, target) in blend.iter_mut().zip(b.iter()) {
+        *value += (target - *value) * amount;
+    }
+    blend
+}
+
+pub fn approx_eq(a: &Matrix, b: &Matrix) -> bool {
+    a.iter().zip(b.iter()).all(|(a, b)| (a - b).abs() < 1e-6)
+}
+
+/// gamma table with `size` entries that scales a channel by `scale` and adds `offset`
+pub fn ramp(size: u32, scale: f64, offset: f64) -> Vec<u16> {
+    let last = (size.max(2) - 1) as f64;
+    (0..size)
+        .map(|i| ((i as f64 * scale + offset * last) * 65535.0 / last).clamp(0.0, 65535.0) as u16)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assert_close(a: [f64; 3], b: [f64; 3]) {
+        assert!(a.iter().zip(b.iter()).all(|(a, b)| (a - b).abs() < 0.005), "{:?} isn't {:?}", a, b);
+    }
+
+    #[test]
+    fn neutral_temperature_is_white() {
+        assert_eq!(temperature(NEUTRAL_KELVIN), [1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn warm_temperatures() {
+        assert_close(temperature(4500), [1.0, 0.856, 0.750]);
+        assert_close(temperature(3400), [1.0, 0.746, 0.541]);
+        // candle light has no blue at all
+        assert_close(temperature(1900), [1.0, 0.519, 0.0]);
+        // warmer is less blue and less green
+        let temperatures: Vec<[f64; 3]> = (1000..=6500).step_by(500).map(temperature).collect();
+        assert!(temperatures.windows(2).all(|pair| pair[0][2] <= pair[1][2] && pair[0][1] <= pair[1][1]));
+    }
+
+    #[test]
+    fn cool_temperature_takes_away_red() {
+        assert_close(temperature(9000), [0.822, 0.877, 1.0]);
+    }
+
+    #[test]
+    fn ramps() {
+        assert_eq!(ramp(3, 1.0, 0.0), vec![0, 0x7fff, 0xffff]);
+        assert_eq!(ramp(256, 1.0, 0.0)[255], 0xffff);
+        assert_eq!(ramp(5, 0.5, 0.0), vec![0, 8191, 16383, 24575, 32767]);
+        assert!(ramp(0, 1.0, 0.0).is_empty());
+        // a warm ramp is a dimmer copy of the linear one per channel
+        let [red, green, blue] = temperature(3400);
+        assert_eq!(ramp(256, red, 0.0), ramp(256, 1.0, 0.0));
+        assert_eq!(ramp(256, green, 0.0)[255], (green * 65535.0) as u16);
+        assert!(ramp(256, blue, 0.0).iter().zip(ramp(256, green, 0.0).iter()).all(|(blue, green)| blue <= green));
+    }
+
+    #[test]
+    fn grayscale_effect() {
+        let effect = Effect::default();
+        assert!(approx_eq(&effect.matrix(0.0), &IDENTITY));
+        assert!(approx_eq(&effect.matrix(1.0), &GRAYSCALE));
+        assert_eq!(effect.gamma(1.0), Transform::IDENTITY);
+    }
+
+    #[test]
+    fn warm_effect() {
+        let effect = Effect { grayscale: false, temperature: Some(3400), ..Effect::default() };
+        let full = temperature(3400);
+        assert!(approx_eq(&effect.matrix(1.0), &diagonal(full)));
+        assert_eq!(effect.gamma(1.0).scale(), full);
+        let half = effect.gamma(0.5).scale();
+        assert!((half[2] - (1.0 + full[2]) / 2.0).abs() < 1e-9);
+        assert_eq!(effect.gamma(0.0), Transform::IDENTITY);
+    }
+
+    #[test]
+    fn warm_grayscale_effect() {
+        let effect = Effect { temperature: Some(3400), ..Effect::default() };
+        let full = temperature(3400);
+        let matrix = effect.matrix(1.0);
+        // gray first, then warm, so every row is the luminance scaled by its channel
+        for (row, scale) in matrix.chunks(3).zip(full.iter()) {
+            assert!((row.iter().sum::<f64>() - scale).abs() < 1e-9);
+            assert!((row[1] - 0.7152 * scale).abs() < 1e-9);
+        }
+        // gamma tables can't desaturate so they only get the warm part
+        assert_eq!(effect.gamma(1.0).scale(), full);
+    }
+
+    #[test]
+    fn parses_effects() {
+        let warm: Effect = serde_yaml::from_str("temperature: 3400").unwrap();
+        assert_eq!(warm, Effect { temperature: Some(3400), ..Effect::default() });
+        let warm: Effect = serde_yaml::from_str("grayscale: false\ntemperature: 3400").unwrap();
+        assert_eq!(warm, Effect { grayscale: false, temperature: Some(3400), ..Effect::default() });
+        assert_eq!(
+            serde_yaml::to_string(&Effect::default()).unwrap(),
+            "---\ngrayscale: true\nbrightness_floor: 0.3\n",
+        );
+        let dim: Effect = serde_yaml::from_str("grayscale: false\nbrightness: 0.5\nbrightness_floor: 0.6").unwrap();
+        assert_eq!(dim.transform(1.0).scale(), [0.6; 3]);
+    }
+
+    #[test]
+    fn intensity_from_matrix() {
+        let warm = Effect { temperature: Some(2700), ..Effect::default() };
+        assert_eq!(warm.intensity_of(&IDENTITY), Some(0.0));
+        assert!((warm.intensity_of(&warm.matrix(0.3)).unwrap() - 0.3).abs() < 1e-9);
+        assert!((warm.intensity_of(&warm.matrix(1.0)).unwrap() - 1.0).abs() < 1e-9);
+        // some other effect doesn't count
+        assert_eq!(warm.intensity_of(&GRAYSCALE), None);
+        assert_eq!(warm.intensity_of(&diagonal([0.5, 0.5, 0.5])), None);
+    }
+
+    #[test]
+    fn intensity_from_ramps() {
+        let warm = Effect { temperature: Some(2700), brightness: Some(0.5), ..Effect::default() };
+        assert_eq!(warm.intensity_of_ramps(&Transform::IDENTITY.ramps(256)), Some(0.0));
+        assert!((warm.intensity_of_ramps(&warm.gamma(0.3).ramps(256)).unwrap() - 0.3).abs() < 1e-3);
+        assert!((warm.intensity_of_ramps(&warm.gamma(1.0).ramps(1024)).unwrap() - 1.0).abs() < 1e-3);
+        // some other tables don't count
+        assert_eq!(warm.intensity_of_ramps(&Transform::linear(diagonal([0.5; 3])).ramps(256)), None);
+        let empty = [Vec::new(), Vec::new(), Vec::new()];
+        assert_eq!(warm.intensity_of_ramps(&empty), None);
+    }
+
+    #[test]
+    fn dim_effect() {
+        let dim = Effect { grayscale: false, brightness: Some(0.5), ..Effect::default() };
+        assert!(dim.has_gamma());
+        assert_eq!(dim.gamma(1.0).scale(), [0.5; 3]);
+        assert_eq!(dim.gamma(0.5).scale(), [0.75; 3]);
+        assert!(approx_eq(&dim.matrix(1.0), &diagonal([0.5; 3])));
+        assert_eq!(ramp(5, dim.gamma(1.0).scale()[0], 0.0), vec![0, 8191, 16383, 24575, 32767]);
+        assert!(!Effect::default().has_gamma());
+    }
+
+    #[test]
+    fn dimming_stops_at_the_floor() {
+        let dark = Effect { brightness: Some(0.05), ..Effect::default() };
+        assert!(approx_eq(&dark.gamma(1.0).matrix, &diagonal([0.3; 3])));
+        assert!(approx_eq(&dark.matrix(1.0), &multiply(&diagonal([0.3; 3]), &GRAYSCALE)));
+        let nonsense = Effect { brightness: Some(2.0), brightness_floor: -1.0, ..Effect::default() };
+        assert_eq!(nonsense.gamma(1.0), Transform::IDENTITY);
+    }
+
+    #[test]
+    fn dimming_composes_with_grayscale_and_warmth() {
+        let effect = Effect { temperature: Some(3400), brightness: Some(0.5), ..Effect::default() };
+        let warm = temperature(3400);
+        // one matrix that grays, warms and dims, so white ends up at half the warm white
+        for (row, scale) in effect.matrix(1.0).chunks(3).zip(warm.iter()) {
+            assert!((row.iter().sum::<f64>() - scale / 2.0).abs() < 1e-9);
+        }
+        for (gamma, scale) in effect.gamma(1.0).scale().iter().zip(warm.iter()) {
+            assert!((gamma - scale / 2.0).abs() < 1e-9);
+        }
+        assert!((effect.intensity_of(&effect.matrix(0.4)).unwrap() - 0.4).abs() < 1e-9);
+    }
+
+    #[test]
+    fn shorthands_come_before_the_pipeline() {
+        let effect: Effect = serde_yaml::from_str(
+            "temperature: 3400\nbrightness: 0.1\npipeline:\n  - invert\n  - dim: 0.8\n  - tint: [1.0, 0.5, 0.5]",
+        ).unwrap();
+        assert_eq!(effect.ops(), vec![
+            Op::Desaturate(1.0),
+            Op::Temperature(3400),
+            Op::Dim(0.1),
+            Op::Invert,
+            Op::Dim(0.8),
+            Op::Tint([1.0, 0.5, 0.5]),
+        ]);
+        assert_eq!(Effect::default().ops(), vec![Op::Desaturate(1.0)]);
+    }
+
+    #[test]
+    fn parses_every_op() {
+        let ops: Vec<Op> = serde_yaml::from_str(
+            "- desaturate: 0.5\n- tint: [1, 0.9, 0.8]\n- temperature: 2700\n- invert\n- dim: 0.6\n\
+             - matrix: [0, 1, 0, 1, 0, 0, 0, 0, 1]",
+        ).unwrap();
+        assert_eq!(ops, vec![
+            Op::Desaturate(0.5),
+            Op::Tint([1.0, 0.9, 0.8]),
+            Op::Temperature(2700),
+            Op::Invert,
+            Op::Dim(0.6),
+            Op::Matrix([0.0, 1.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0]),
+        ]);
+    }
+
+    #[test]
+    fn rejects_ops_out_of_range() {
+        let out_of_range = [
+            ("desaturate: 1.5", "between 0 and 1"),
+            ("dim: -1", "between 0 and 1"),
+            ("tint: [1, .inf, 1]", "finite"),
+            ("matrix: [.nan, 0, 0, 0, 1, 0, 0, 0, 1]", "finite"),
+        ];
+        for (op, message) in out_of_range.iter() {
+            let err = serde_yaml::from_str::<Vec<Op>>(&format!("- {}", op)).unwrap_err();
+            assert!(err.to_string().contains(message), "{}: {}", op, err);
+        }
+    }
+
+    #[test]
+    fn golden_half_desaturate_then_invert() {
+        let effect = Effect {
+            grayscale: false,
+            pipeline: vec![Op::Desaturate(0.5), Op::Invert],
+            ..Effect::default()
+        };
+        let transform = effect.transform(1.0);
+        assert!(approx_eq(&transform.matrix, &[
+            -0.6063, -0.3576, -0.0361,
+            -0.1063, -0.8576, -0.0361,
+            -0.1063, -0.3576, -0.5361,
+        ]));
+        assert_eq!(transform.offset, [1.0; 3]);
+        // a matrix only gets the desaturating
+        assert!(effect.has_offset());
+        assert!(approx_eq(&effect.matrix(1.0), &lerp(&IDENTITY, &GRAYSCALE, 0.5)));
+        // gamma tables only get the inverting
+        assert_eq!(effect.gamma(1.0).ramps(5), [
+            vec![65535, 49151, 32767, 16383, 0],
+            vec![65535, 49151, 32767, 16383, 0],
+            vec![65535, 49151, 32767, 16383, 0],
+        ]);
+        assert!(effect.has_gamma());
+        assert!(!effect.is_per_channel());
+    }
+
+    #[test]
+    fn golden_tint_invert_dim() {
+        let effect = Effect {
+            grayscale: false,
+            brightness_floor: 0.0,
+            pipeline: vec![Op::Tint([1.0, 0.5, 0.0]), Op::Invert, Op::Dim(0.5)],
+            ..Effect::default()
+        };
+        assert!(effect.is_per_channel());
+        let transform = effect.transform(1.0);
+        assert!(approx_eq(&transform.matrix, &diagonal([-0.5, -0.25, 0.0])));
+        assert_eq!(transform.offset, [0.5; 3]);
+        assert_eq!(transform.ramps(3), [
+            vec![32767, 16383, 0],
+            vec![32767, 24575, 16383],
+            vec![32767, 32767, 32767],
+        ]);
+        assert_eq!(transform.channel(1, 0.5), 0.375);
+        // halfway there is halfway between leaving it alone and all of it
+        let half = effect.transform(0.5);
+        assert!(approx_eq(&half.matrix, &diagonal([0.25, 0.375, 0.5])));
+        assert_eq!(half.offset, [0.25; 3]);
+        assert_eq!(effect.transform(0.0), Transform::IDENTITY);
+    }
+
+    #[test]
+    fn golden_user_matrix_after_warmth() {
+        let swap = [
+            0.0, 1.0, 0.0,
+            1.0, 0.0, 0.0,
+            0.0, 0.0, 1.0,
+        ];
+        let effect = Effect {
+            grayscale: false,
+            pipeline: vec![Op::Tint([0.8, 0.6, 0.4]), Op::Matrix(swap)],
+            ..Effect::default()
+        };
+        assert!(approx_eq(&effect.matrix(1.0), &[
+            0.0, 0.6, 0.0,
+            0.8, 0.0, 0.0,
+            0.0, 0.0, 0.4,
+        ]));
+        assert!(!effect.transform(1.0).has_offset());
+        assert_eq!(effect.gamma(1.0).scale(), [0.8, 0.6, 0.4]);
+    }
+
+    #[test]
+    fn transforms_compose_in_order() {
+        let invert = Op::Invert.transform(0.0);
+        let dim = Op::Dim(0.5).transform(0.0);
+        // dimming then inverting leaves everything at least half bright
+        assert_eq!(dim.then(&invert).channel(0, 1.0), 0.5);
+        assert_eq!(dim.then(&invert).channel(0, 0.0), 1.0);
+        // inverting then dimming leaves everything at most half bright
+        assert_eq!(invert.then(&dim).channel(0, 0.0), 0.5);
+        assert_eq!(invert.then(&dim).channel(0, 1.0), 0.0);
+        assert_eq!(Transform::IDENTITY.then(&invert), invert);
+    }
+
+    #[test]
+    fn multiply_applies_right_first() {
+        let swap = [
+            0.0, 1.0, 0.0,
+            1.0, 0.0, 0.0,
+            0.0, 0.0, 1.0,
+        ];
+        let scale = diagonal([2.0, 3.0, 4.0]);
+        assert_eq!(multiply(&scale, &swap), [
+            0.0, 2.0, 0.0,
+            3.0, 0.0, 0.0,
+            0.0, 0.0, 4.0,
+        ]);
+        assert_eq!(multiply(&IDENTITY, &GRAYSCALE), GRAYSCALE);
+    }
+}
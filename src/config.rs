@@ -1,19 +1,531 @@
-use serde::{Serialize, Deserialize};
+use std::{
+    error::Error,
+    fmt,
+    fs,
+    io,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+use serde::{Serialize, Deserialize, Deserializer, de::{self, Visitor}};
+use serde_yaml::{Mapping, Value};
+use notify::{watcher, DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use log::info;
+use crate::color::Effect;
+use crate::schedule::{Schedule, Weekdays, WeeklySchedule};
+use crate::solar::Location;
 use crate::timerange::TimeRange;
 
+/// the layout of the config file, older files are upgraded to it when they're loaded
+pub const VERSION: u64 = 2;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
-    pub nighttime: TimeRange,
+    pub version: u64,
+    pub nighttime: Nighttime,
+    /// needed for ranges relative to sunrise and sunset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<Location>,
+    /// longest the scheduler sleeps between checking the clock, which is
+    /// how late a jump of the wall clock might be noticed
+    #[serde(deserialize_with = "at_least_one")]
     pub loop_seconds: u64,
+    /// how long to fade the effect in before nighttime and back out after it,
+    /// on displays that can only switch it on and off it happens at the boundary
+    ///
+    /// files from before there was fading don't have it and keep switching at the boundary,
+    /// new ones get `DEFAULT_FADE_MINUTES`
+    #[serde(default, deserialize_with = "up_to_a_day")]
+    pub fade_minutes: u64,
+    /// what nighttime does to the display, grayscale unless it says otherwise
+    #[serde(default)]
+    pub effect: Effect,
     pub title: String,
 }
 
+/// when it's nighttime over the week
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Nighttime {
+    /// for every day that doesn't have its own in `weekdays`,
+    /// either one range or a list of them
+    pub every_day: Schedule,
+    #[serde(default)]
+    pub weekdays: Weekdays<Schedule>,
+}
+
 impl ::std::default::Default for Config {
     fn default() -> Self {
         Self {
-            nighttime: TimeRange::from_hmhm(0, 30, 10, 00),
+            version: VERSION,
+            nighttime: Nighttime {
+                every_day: TimeRange::from_hmhm(0, 30, 10, 00).into(),
+                weekdays: Weekdays::default(),
+            },
+            location: None,
             loop_seconds: 60,
+            fade_minutes: DEFAULT_FADE_MINUTES,
+            effect: Effect::default(),
             title: "🌚".to_owned(),
         }
     }
 }
+
+/// how long a fade takes in a new config file
+const DEFAULT_FADE_MINUTES: u64 = 30;
+
+/// a fade can't take longer than the day it's in
+fn up_to_a_day<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    number(deserializer, 0.0..=24.0 * 60.0, "has to be a whole number of minutes up to a day").map(|minutes| minutes as u64)
+}
+
+/// checking the clock all the time would keep a core busy
+fn at_least_one<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    number(deserializer, 1.0..=u64::MAX as f64, "has to be a whole number of at least 1").map(|seconds| seconds as u64)
+}
+
+/// read a number that has to be within `range` and fail with `message` if it isn't,
+/// checking it while it's being read means the error points at the number in the file
+pub fn number<'de, D: Deserializer<'de>>(
+    deserializer: D,
+    range: RangeInclusive<f64>,
+    message: &str,
+) -> Result<f64, D::Error> {
+    struct NumberVisitor<'a> {
+        range: RangeInclusive<f64>,
+        message: &'a str,
+    }
+
+    impl<'de> Visitor<'de> for NumberVisitor<'_> {
+        type Value = f64;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a number")
+        }
+
+        fn visit_f64<E: de::Error>(self, value: f64) -> Result<f64, E> {
+            if self.range.contains(&value) { Ok(value) } else { Err(E::custom(self.message)) }
+        }
+
+        fn visit_u64<E: de::Error>(self, value: u64) -> Result<f64, E> {
+            self.visit_f64(value as f64)
+        }
+
+        fn visit_i64<E: de::Error>(self, value: i64) -> Result<f64, E> {
+            self.visit_f64(value as f64)
+        }
+    }
+
+    deserializer.deserialize_f64(NumberVisitor { range, message })
+}
+
+impl Config {
+    pub fn schedule(&self) -> WeeklySchedule {
+        WeeklySchedule::new(self.nighttime.every_day.clone(), self.nighttime.weekdays.clone(), self.location)
+    }
+}
+
+/// why the config file can't be used
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    /// it isn't yaml or doesn't fit the config, the message says where
+    Parse(serde_yaml::Error),
+    /// written by a newer version of the app, or made up
+    Version(u64),
+}
+
+impl ConfigError {
+    /// line and column in the file where it went wrong, both starting at 1
+    #[allow(unused)]
+    pub fn location(&self) -> Option<(usize, usize)> {
+        match self {
+            ConfigError::Parse(err) => err.location().map(|location| (location.line(), location.column())),
+            ConfigError::Io(_) | ConfigError::Version(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "can't read config file: {}", err),
+            ConfigError::Parse(err) => write!(f, "{}", err),
+            ConfigError::Version(version) => {
+                write!(f, "config file is version {}, only versions 1 to {} can be read", version, VERSION)
+            },
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+impl From<io::Error> for ConfigError {
+    fn from(err: io::Error) -> Self {
+        ConfigError::Io(err)
+    }
+}
+
+/// read the config at `path`, on the first run there's none yet and it gets the default one,
+/// a file from an older version is upgraded in place and the original kept next to it
+pub fn load(path: &Path) -> Result<Config, ConfigError> {
+    if !path.exists() {
+        let config = Config::default();
+        store(path, &config)?;
+        return Ok(config);
+    }
+    let yaml = fs::read_to_string(path)?;
+    let (version, upgraded) = match migrate(&yaml)? {
+        Some(migrated) => migrated,
+        None => return parse(&yaml),
+    };
+    // only touch the file once we know the upgrade loads
+    let config = parse(&upgraded)?;
+    let mut original = path.as_os_str().to_owned();
+    original.push(format!(".v{}", version));
+    fs::copy(path, &original)?;
+    fs::write(path, upgraded)?;
+    info!("upgraded config file from version {}, the original is at {}", version, PathBuf::from(original).display());
+    Ok(config)
+}
+
+/// read the config at `path` the way `load` would, but without writing anything
+pub fn check(path: &Path) -> Result<Config, ConfigError> {
+    let yaml = fs::read_to_string(path)?;
+    match migrate(&yaml)? {
+        Some((_, upgraded)) => parse(&upgraded),
+        None => parse(&yaml),
+    }
+}
+
+fn parse(yaml: &str) -> Result<Config, ConfigError> {
+    serde_yaml::from_str(yaml).map_err(ConfigError::Parse)
+}
+
+/// each one upgrades a config from the version after its index to the next one
+const MIGRATIONS: [fn(&mut Mapping); VERSION as usize - 1] = [
+    weekdays_into_nighttime,
+];
+
+/// bring `yaml` up to the current version, `None` if it already is
+///
+/// files from before there were versions count as version 1, the ones that aren't
+/// a mapping at all are left for `parse` to say what's wrong with them
+fn migrate(yaml: &str) -> Result<Option<(u64, String)>, ConfigError> {
+    let config = match serde_yaml::from_str(yaml).map_err(ConfigError::Parse)? {
+        Value::Mapping(config) => config,
+        _ => return Ok(None),
+    };
+    let version = match config.get(&"version".into()) {
+        None => 1,
+        Some(Value::Number(version)) => match version.as_u64() {
+            Some(version) => version,
+            None => return Ok(None),
+        },
+        Some(_) => return Ok(None),
+    };
+    if version == VERSION {
+        return Ok(None);
+    }
+    if version == 0 || version > VERSION {
+        return Err(ConfigError::Version(version));
+    }
+
+    let mut config = config;
+    for migration in &MIGRATIONS[version as usize - 1..] {
+        migration(&mut config);
+    }
+    // the version goes first so it's the first thing someone opening the file sees
+    let mut upgraded = Mapping::new();
+    upgraded.insert("version".into(), VERSION.into());
+    upgraded.extend(config.into_iter().filter(|(key, _)| key.as_str() != Some("version")));
+    let upgraded = serde_yaml::to_string(&upgraded).map_err(ConfigError::Parse)?;
+    Ok(Some((version, upgraded)))
+}
+
+/// version 2 keeps everything about when it's nighttime together,
+/// in 1 the every day schedule and the weekdays were both at the top
+fn weekdays_into_nighttime(config: &mut Mapping) {
+    let mut nighttime = Mapping::new();
+    if let Some(every_day) = config.remove(&"nighttime".into()) {
+        nighttime.insert("every_day".into(), every_day);
+    }
+    if let Some(weekdays) = config.remove(&"weekdays".into()) {
+        nighttime.insert("weekdays".into(), weekdays);
+    }
+    config.insert("nighttime".into(), nighttime.into());
+}
+
+pub fn store(path: &Path, config: &Config) -> Result<(), ConfigError> {
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+    let yaml = serde_yaml::to_string(config).map_err(ConfigError::Parse)?;
+    Ok(fs::write(path, yaml)?)
+}
+
+/// copy a config that doesn't load next to it, so whatever happens to the file
+/// later there's still the version that went wrong to look at
+pub fn back_up(path: &Path) -> io::Result<PathBuf> {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".broken");
+    let backup = PathBuf::from(backup);
+    fs::copy(path, &backup)?;
+    Ok(backup)
+}
+
+/// how long the file has to stay untouched before we read it, editors often save in a few steps
+const SETTLE: Duration = Duration::from_millis(500);
+
+/// read the config at `path` again every time it's saved and hand it to `on_change`,
+/// or the error if it doesn't parse so the caller can keep the config it has,
+/// it's watched for as long as the returned watcher is around
+pub fn watch<F>(path: PathBuf, on_change: F) -> Result<RecommendedWatcher, Box<dyn Error>>
+    where F: Fn(Result<Config, ConfigError>) + Send + 'static,
+{
+    let (events, receiver) = mpsc::channel();
+    let mut watcher = watcher(events, SETTLE)?;
+    // editors tend to save by writing a new file and moving it over the old one,
+    // which a watch on the file itself doesn't survive, so watch the directory instead
+    let directory = path.parent().ok_or("config file has no directory")?;
+    watcher.watch(directory, RecursiveMode::NonRecursive)?;
+    thread::spawn(move || {
+        for event in receiver {
+            let changed = match event {
+                DebouncedEvent::Create(changed)
+                | DebouncedEvent::Write(changed)
+                | DebouncedEvent::Rename(_, changed) => changed,
+                _ => continue,
+            };
+            // loading a missing file would write the default config over it
+            if changed.file_name() == path.file_name() && path.exists() {
+                on_change(load(&path));
+            }
+        }
+    });
+    Ok(watcher)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, sync::mpsc::Receiver};
+    use super::*;
+
+    fn watched() -> (tempfile::TempDir, PathBuf, RecommendedWatcher, Receiver<Result<Config, ConfigError>>) {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("config.yaml");
+        fs::write(&path, serde_yaml::to_string(&Config::default()).unwrap()).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let watcher = watch(path.clone(), move |config| sender.send(config).unwrap()).unwrap();
+        (directory, path, watcher, receiver)
+    }
+
+    fn next(receiver: &Receiver<Result<Config, ConfigError>>) -> Result<Config, ConfigError> {
+        receiver.recv_timeout(Duration::from_secs(10)).expect("config wasn't reloaded")
+    }
+
+    #[test]
+    fn reloads_when_saved() {
+        let (_directory, path, _watcher, receiver) = watched();
+        let edited = fs::read_to_string(&path).unwrap().replace("loop_seconds: 60", "loop_seconds: 5");
+        fs::write(&path, edited).unwrap();
+        let config = next(&receiver).unwrap();
+        assert_eq!(config.loop_seconds, 5);
+        assert_eq!(config.title, "🌚");
+    }
+
+    #[test]
+    fn reloads_when_replaced() {
+        let (directory, path, _watcher, receiver) = watched();
+        let replacement = directory.path().join(".config.yaml.swp");
+        let edited = fs::read_to_string(&path).unwrap().replace("🌚", "🌙");
+        fs::write(&replacement, edited).unwrap();
+        fs::rename(&replacement, &path).unwrap();
+        assert_eq!(next(&receiver).unwrap().title, "🌙");
+    }
+
+    #[test]
+    fn hands_over_parse_errors() {
+        let (_directory, path, _watcher, receiver) = watched();
+        fs::write(&path, "nighttime: [").unwrap();
+        assert!(next(&receiver).is_err());
+        // the file is left alone for the user to fix
+        assert_eq!(fs::read_to_string(&path).unwrap(), "nighttime: [");
+    }
+
+    #[test]
+    fn writes_the_default_config_on_the_first_run() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("nighttime").join("config.yaml");
+        assert_eq!(load(&path).unwrap().loop_seconds, 60);
+        assert_eq!(load(&path).unwrap().title, "🌚");
+    }
+
+    #[test]
+    fn says_where_the_error_is() {
+        let err = parse(
+            "version: 2\ntitle: x\nloop_seconds: 60\nnighttime:\n  every_day:\n    start: \"23:00\"\n    end: sunrise + 25h\n",
+        ).unwrap_err();
+        assert_eq!(err.location(), Some((7, 10)));
+        assert!(err.to_string().contains("at line 7 column 10"), "{}", err);
+
+        // the same in a list and for a weekday
+        let err = parse(
+            "version: 2\ntitle: x\nloop_seconds: 60\nnighttime:\n  every_day:\n    - start: \"23:00\"\n      end: \"07:00\"\n    - start: 25:00\n      end: \"07:00\"\n",
+        ).unwrap_err();
+        assert_eq!(err.location().map(|(line, _)| line), Some(8));
+        let err = parse(
+            "version: 2\ntitle: x\nloop_seconds: 60\nnighttime:\n  every_day: []\n  weekdays:\n    sat:\n      start: \"23:00\"\n      end: soon\n",
+        ).unwrap_err();
+        assert_eq!(err.location().map(|(line, _)| line), Some(9));
+
+        let err = parse("version: 2\ntitle: x\nloop_seconds: 60\nnighttime: [\n").unwrap_err();
+        assert!(err.location().is_some());
+    }
+
+    #[test]
+    fn checks_values() {
+        let valid = "version: 2\ntitle: x\nnighttime:\n  every_day: []\n";
+        let err = parse(&format!("{}loop_seconds: 0\n", valid)).unwrap_err();
+        assert_eq!(err.location(), Some((5, 15)));
+        assert!(err.to_string().contains("at least 1"), "{}", err);
+
+        let err = parse(&format!("{}loop_seconds: 60\neffect:\n  temperature: 100\n", valid)).unwrap_err();
+        assert_eq!(err.location().map(|(line, _)| line), Some(7));
+        assert!(err.to_string().contains("kelvin"), "{}", err);
+        let err = parse(&format!("{}loop_seconds: 60\neffect:\n  brightness: 1.5\n", valid)).unwrap_err();
+        assert_eq!(err.location().map(|(line, _)| line), Some(7));
+        let err = parse(&format!("{}loop_seconds: 60\nlocation:\n  latitude: 91\n  longitude: 0\n", valid));
+        assert_eq!(err.unwrap_err().location().map(|(line, _)| line), Some(7));
+
+        let err = parse(&format!("{}loop_seconds: 60\nfade_minutes: 18446744073709551615\n", valid)).unwrap_err();
+        assert_eq!(err.location().map(|(line, _)| line), Some(6));
+        assert!(err.to_string().contains("up to a day"), "{}", err);
+
+        assert!(parse(&format!("{}loop_seconds: 60\n", valid)).is_ok());
+    }
+
+    /// load a fixture from a copy, so upgrading it doesn't change the original
+    fn load_fixture(yaml: &str) -> (tempfile::TempDir, PathBuf, Config) {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("config.yaml");
+        fs::write(&path, yaml).unwrap();
+        let config = load(&path).unwrap();
+        (directory, path, config)
+    }
+
+    /// what the config looked like as the app went along, before it had a version
+    #[test]
+    fn upgrades_version_1() {
+        let (directory, path, config) = load_fixture(include_str!("../fixtures/config/v1-baseline.yaml"));
+        assert_eq!(config.version, VERSION);
+        assert_eq!(config.nighttime.every_day.to_string(), "00:30-10:00");
+        assert_eq!(config.loop_seconds, 60);
+        assert_eq!(config.title, "🌚");
+        // the file is upgraded and the original kept
+        assert_eq!(parse(&fs::read_to_string(&path).unwrap()).unwrap().nighttime.every_day.to_string(), "00:30-10:00");
+        assert!(fs::read_to_string(&path).unwrap().starts_with("---\nversion: 2\n"));
+        assert_eq!(
+            fs::read_to_string(directory.path().join("config.yaml.v1")).unwrap(),
+            include_str!("../fixtures/config/v1-baseline.yaml"),
+        );
+
+        let (_directory, _path, config) = load_fixture(include_str!("../fixtures/config/v1-weekdays.yaml"));
+        assert_eq!(config.nighttime.every_day.to_string(), "23:00-07:00");
+        assert_eq!(config.nighttime.weekdays.fri.unwrap().to_string(), "01:00-10:00");
+        assert_eq!(config.nighttime.weekdays.sat.unwrap().to_string(), "01:00-11:00");
+
+        let (_directory, _path, config) = load_fixture(include_str!("../fixtures/config/v1-several-ranges.yaml"));
+        assert_eq!(config.nighttime.every_day.to_string(), "13:00-14:00 23:00-07:00");
+        assert_eq!(config.nighttime.weekdays.sun.unwrap().ranges().len(), 2);
+
+        let (_directory, _path, config) = load_fixture(include_str!("../fixtures/config/v1-solar.yaml"));
+        assert!(config.schedule().is_solar());
+        assert!(config.location.is_some());
+
+        // it switched at the boundary before there was fading, and still does
+        assert_eq!(config.fade_minutes, 0);
+
+        let (_directory, _path, config) = load_fixture(include_str!("../fixtures/config/v1-effect.yaml"));
+        assert_eq!(config.fade_minutes, 45);
+        assert_eq!(config.effect.temperature, Some(3400));
+        assert_eq!(config.effect.pipeline.len(), 2);
+    }
+
+    #[test]
+    fn loads_the_current_version_as_it_is() {
+        let yaml = include_str!("../fixtures/config/v2.yaml");
+        let (directory, path, config) = load_fixture(yaml);
+        assert_eq!(config.nighttime.every_day.to_string(), "23:00-07:00");
+        assert_eq!(config.nighttime.weekdays.sat.unwrap().to_string(), "01:00-11:00");
+        assert_eq!(fs::read_to_string(&path).unwrap(), yaml);
+        assert!(!directory.path().join("config.yaml.v2").exists());
+
+        // and what it writes itself reads back the same
+        let yaml = serde_yaml::to_string(&Config::default()).unwrap();
+        assert_eq!(migrate(&yaml).unwrap(), None);
+        assert_eq!(parse(&yaml).unwrap().nighttime.every_day.to_string(), "00:30-10:00");
+        assert_eq!(parse(&yaml).unwrap().fade_minutes, DEFAULT_FADE_MINUTES);
+    }
+
+    #[test]
+    fn checks_without_writing() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("config.yaml");
+        assert!(matches!(check(&path), Err(ConfigError::Io(_))));
+        assert!(!path.exists());
+
+        let yaml = include_str!("../fixtures/config/v1-baseline.yaml");
+        fs::write(&path, yaml).unwrap();
+        assert_eq!(check(&path).unwrap().version, VERSION);
+        assert_eq!(fs::read_to_string(&path).unwrap(), yaml);
+    }
+
+    #[test]
+    fn refuses_unknown_versions() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("config.yaml");
+        for version in &[0, VERSION + 1] {
+            let yaml = format!("version: {}\ntitle: x\nloop_seconds: 60\nnighttime: []\n", version);
+            fs::write(&path, &yaml).unwrap();
+            match load(&path) {
+                Err(ConfigError::Version(found)) => assert_eq!(found, *version),
+                other => panic!("{:?}", other),
+            }
+            // it's left for a version that knows it
+            assert_eq!(fs::read_to_string(&path).unwrap(), yaml);
+        }
+    }
+
+    #[test]
+    fn leaves_broken_old_files_alone() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("config.yaml");
+        let yaml = "title: x\nloop_seconds: 0\nnighttime:\n  start: \"23:00\"\n  end: \"07:00\"\n";
+        fs::write(&path, yaml).unwrap();
+        assert!(load(&path).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), yaml);
+        assert!(!directory.path().join("config.yaml.v1").exists());
+    }
+
+    #[test]
+    fn backs_up_broken_files() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("config.yaml");
+        fs::write(&path, "nighttime: [").unwrap();
+        assert!(load(&path).is_err());
+        let backup = back_up(&path).unwrap();
+        assert_eq!(backup, directory.path().join("config.yaml.broken"));
+        assert_eq!(fs::read_to_string(backup).unwrap(), "nighttime: [");
+        // and the original stays as it was
+        assert_eq!(fs::read_to_string(&path).unwrap(), "nighttime: [");
+    }
+
+    #[test]
+    fn ignores_other_files() {
+        let (directory, _path, _watcher, receiver) = watched();
+        fs::write(directory.path().join("notes.txt"), "hello").unwrap();
+        assert!(receiver.recv_timeout(SETTLE * 4).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+    process,
+};
+use fs2::FileExt;
+
+/// held while the app is running, so a second one doesn't fight it over the display
+///
+/// it's a lock on a file with our pid in it, the system lets go of it when we exit
+/// however that happens, so a run that crashed never leaves it locked
+pub struct InstanceLock {
+    // the lock goes with the file
+    #[allow(unused)]
+    file: File,
+}
+
+#[derive(Debug)]
+pub enum LockError {
+    /// someone else has it, with their pid if they got to write it down
+    Running(Option<u32>),
+    Io(io::Error),
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockError::Running(Some(pid)) => write!(f, "goodnight is already running with pid {}", pid),
+            LockError::Running(None) => write!(f, "goodnight is already running"),
+            LockError::Io(err) => write!(f, "can't take the lock: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for LockError {}
+
+impl From<io::Error> for LockError {
+    fn from(err: io::Error) -> Self {
+        LockError::Io(err)
+    }
+}
+
+impl InstanceLock {
+    /// take the lock at `path` for as long as this lives
+    pub fn acquire(path: &Path) -> Result<Self, LockError> {
+        let mut file = open(path)?;
+        if file.try_lock_exclusive().is_err() {
+            return Err(LockError::Running(pid(&mut file)));
+        }
+        // whatever a run that crashed left in it is stale now
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", process::id())?;
+        file.sync_all()?;
+        Ok(Self { file })
+    }
+
+    /// whether the app is running, without taking the lock
+    pub fn check(path: &Path) -> Result<(), LockError> {
+        let mut file = match open(path) {
+            Ok(file) => file,
+            // nobody could have taken it where it can't even be created
+            Err(_) => return Ok(()),
+        };
+        if file.try_lock_shared().is_err() {
+            return Err(LockError::Running(pid(&mut file)));
+        }
+        file.unlock()?;
+        Ok(())
+    }
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)
+}
+
+fn pid(file: &mut File) -> Option<u32> {
+    let mut pid = String::new();
+    file.read_to_string(&mut pid).ok()?;
+    pid.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn only_one_at_a_time() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("run").join("goodnight.lock");
+        assert!(InstanceLock::check(&path).is_ok());
+        let lock = InstanceLock::acquire(&path).unwrap();
+        match InstanceLock::acquire(&path) {
+            Err(LockError::Running(pid)) => assert_eq!(pid, Some(process::id())),
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+        assert!(matches!(InstanceLock::check(&path), Err(LockError::Running(_))));
+        drop(lock);
+        assert!(InstanceLock::check(&path).is_ok());
+        assert!(InstanceLock::acquire(&path).is_ok());
+    }
+
+    #[test]
+    fn takes_over_what_a_crash_left_behind() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("goodnight.lock");
+        // a crash leaves the file with a pid in it but the system let go of the lock
+        fs::write(&path, "4294967295").unwrap();
+        let lock = InstanceLock::acquire(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), process::id().to_string());
+        drop(lock);
+    }
+}
@@ -0,0 +1,343 @@
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::{Arc, mpsc::{self, Sender}},
+    thread,
+    time::Duration,
+};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use chrono::NaiveTime;
+use log::warn;
+use crate::scheduler::{Command, Lasting, Snooze, State, Transition};
+
+/// the version of the protocol, it goes up when a request or response changes
+/// in a way the other side can't make sense of
+pub const VERSION: u32 = 1;
+
+/// what a client can ask the running app
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum Request {
+    GetState,
+    /// force the effect on or off for as long as `lasting` says, `None` goes back to the schedule
+    SetOverride {
+        on: Option<bool>,
+        /// until the next boundary when it's left out
+        #[serde(default)]
+        lasting: Lasting,
+    },
+    Snooze { minutes: u64 },
+    /// snooze until the next time the clock shows `until`
+    SnoozeUntil { until: NaiveTime },
+    /// read the config file again
+    Reload,
+    /// get a `Transition` every time the display changes from now on, until the connection closes
+    Subscribe,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "response", rename_all = "snake_case")]
+pub enum Response {
+    State(State),
+    Ok,
+    Transition(Transition),
+    Error { message: String },
+}
+
+/// every line on the socket is one of these as json, with the version it's in
+#[derive(Serialize, Deserialize)]
+struct Message<T> {
+    version: u32,
+    #[serde(flatten)]
+    body: T,
+}
+
+/// reads the config again and says what's wrong with it if it can't
+pub type Reload = Arc<dyn Fn() -> Result<(), String> + Send + Sync>;
+
+/// the socket the running app listens on, it's removed when this is dropped
+pub struct Server {
+    path: PathBuf,
+}
+
+impl Server {
+    /// listen on `path` and hand the requests to the scheduler through `commands`
+    ///
+    /// a socket file left behind by an app that didn't get to clean up is replaced,
+    /// one that someone still answers on isn't
+    pub fn start(path: &Path, commands: Sender<Command>, reload: Reload) -> io::Result<Self> {
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+        let listener = match UnixListener::bind(path) {
+            Err(err) if err.kind() == io::ErrorKind::AddrInUse && UnixStream::connect(path).is_err() => {
+                fs::remove_file(path)?;
+                UnixListener::bind(path)?
+            },
+            listener => listener?,
+        };
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!("can't accept a connection on the socket: {}", err);
+                        continue;
+                    },
+                };
+                let (commands, reload) = (commands.clone(), Arc::clone(&reload));
+                thread::spawn(move || {
+                    if let Err(err) = serve(stream, commands, reload) {
+                        warn!("connection on the socket went wrong: {}", err);
+                    }
+                });
+            }
+        });
+        Ok(Self { path: path.to_owned() })
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+/// answer the requests on one connection until it's closed
+fn serve(stream: UnixStream, commands: Sender<Command>, reload: Reload) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let request = match decode::<Request>(&line?) {
+            Ok(request) => request,
+            Err(err) => {
+                send(&mut writer, &Response::Error { message: err.to_string() })?;
+                continue;
+            },
+        };
+        let response = match request {
+            Request::GetState => {
+                let (reply, state) = mpsc::channel();
+                commands.send(Command::GetState(reply)).ok();
+                match state.recv() {
+                    Ok(state) => Response::State(state),
+                    Err(_) => stopped(),
+                }
+            },
+            Request::SetOverride { on, lasting } => {
+                command(&commands, Command::SetOverride(on.map(|on| (on, lasting))))
+            },
+            Request::Snooze { minutes } => {
+                command(&commands, Command::Snooze(Snooze::For(Duration::from_secs(minutes.saturating_mul(60)))))
+            },
+            Request::SnoozeUntil { until } => command(&commands, Command::Snooze(Snooze::Until(until))),
+            Request::Reload => match reload() {
+                Ok(()) => Response::Ok,
+                Err(message) => Response::Error { message },
+            },
+            Request::Subscribe => {
+                let (subscriber, transitions) = mpsc::channel();
+                if commands.send(Command::Subscribe(subscriber)).is_err() {
+                    send(&mut writer, &stopped())?;
+                    continue;
+                }
+                send(&mut writer, &Response::Ok)?;
+                // from here on the connection only goes one way, a client that's
+                // gone is noticed the next time there's something to send
+                for transition in transitions {
+                    send(&mut writer, &Response::Transition(transition))?;
+                }
+                return Ok(());
+            },
+        };
+        send(&mut writer, &response)?;
+    }
+    Ok(())
+}
+
+fn command(commands: &Sender<Command>, command: Command) -> Response {
+    match commands.send(command) {
+        Ok(()) => Response::Ok,
+        Err(_) => stopped(),
+    }
+}
+
+fn stopped() -> Response {
+    Response::Error { message: "the scheduler has stopped".to_owned() }
+}
+
+fn send<T: Serialize>(writer: &mut impl Write, body: &T) -> io::Result<()> {
+    let mut line = serde_json::to_string(&Message { version: VERSION, body })?;
+    line.push('\n');
+    writer.write_all(line.as_bytes())
+}
+
+fn decode<T: DeserializeOwned>(line: &str) -> io::Result<T> {
+    // the version is checked first so a newer client gets told that instead of what it sent being wrong
+    let version: Message<serde_json::Value> = serde_json::from_str(line)?;
+    if version.version != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("protocol version {} isn't supported, this is version {}", version.version, VERSION),
+        ));
+    }
+    let message: Message<T> = serde_json::from_str(line)?;
+    Ok(message.body)
+}
+
+/// a connection to the running app
+pub struct Client {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl Client {
+    /// fails if there's no app listening on `path`
+    pub fn connect(path: &Path) -> io::Result<Self> {
+        let writer = UnixStream::connect(path)?;
+        let reader = BufReader::new(writer.try_clone()?);
+        Ok(Self { reader, writer })
+    }
+
+    /// send `request` and wait for the answer
+    pub fn request(&mut self, request: &Request) -> io::Result<Response> {
+        send(&mut self.writer, request)?;
+        self.receive()
+    }
+
+    /// wait for the next thing the app sends, after subscribing that's the next transition
+    pub fn receive(&mut self) -> io::Result<Response> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        decode(&line)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    use super::*;
+    use crate::backend::{DisplayBackend, RecordingBackend};
+    use crate::clock::SystemClock;
+    use crate::scheduler::{Cause, Expiry, Scheduler};
+    use crate::schedule::{Weekdays, WeeklySchedule};
+    use crate::timerange::TimeRange;
+
+    /// a scheduler that's never at nighttime on a display that isn't there,
+    /// behind a socket in a temporary directory
+    fn running() -> (tempfile::TempDir, PathBuf, Server, Arc<RecordingBackend>, Arc<Mutex<u32>>) {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("goodnight.sock");
+        let backend = Arc::new(RecordingBackend::new(false));
+        let daytime = WeeklySchedule::new(Vec::<TimeRange>::new().into(), Weekdays::default(), None);
+        let scheduler = Scheduler::new(daytime, backend.clone(), SystemClock, Duration::from_secs(0), Duration::from_secs(60));
+        let (commands, receiver) = mpsc::channel();
+        thread::spawn(move || scheduler.run(receiver));
+        let reloads = Arc::new(Mutex::new(0));
+        let counter = Arc::clone(&reloads);
+        let reload: Reload = Arc::new(move || {
+            *counter.lock().unwrap() += 1;
+            Err("not today".to_owned())
+        });
+        let server = Server::start(&path, commands, reload).unwrap();
+        (directory, path, server, backend, reloads)
+    }
+
+    #[test]
+    fn answers_requests() {
+        let (_directory, path, _server, backend, reloads) = running();
+        let mut client = Client::connect(&path).unwrap();
+        match client.request(&Request::GetState).unwrap() {
+            Response::State(state) => {
+                assert!(!state.nighttime);
+                assert_eq!(state.intensity, 0.0);
+                assert_eq!(state.next_boundary, None);
+            },
+            other => panic!("{:?}", other),
+        }
+
+        assert_eq!(client.request(&Request::SetOverride { on: Some(true), lasting: Lasting::NextBoundary }).unwrap(), Response::Ok);
+        match client.request(&Request::GetState).unwrap() {
+            Response::State(state) => assert_eq!(state.intensity, 1.0),
+            other => panic!("{:?}", other),
+        }
+        assert!(backend.is_grayscale());
+        assert_eq!(client.request(&Request::SetOverride { on: None, lasting: Lasting::NextBoundary }).unwrap(), Response::Ok);
+        client.request(&Request::GetState).unwrap();
+        assert!(!backend.is_grayscale());
+
+        assert_eq!(client.request(&Request::Reload).unwrap(), Response::Error { message: "not today".to_owned() });
+        assert_eq!(*reloads.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn snoozes() {
+        let (_directory, path, _server, backend, _reloads) = running();
+        let mut client = Client::connect(&path).unwrap();
+        client.request(&Request::SetOverride { on: Some(true), lasting: Lasting::NextBoundary }).unwrap();
+        assert_eq!(client.request(&Request::Snooze { minutes: 15 }).unwrap(), Response::Ok);
+        match client.request(&Request::GetState).unwrap() {
+            Response::State(state) => {
+                assert_eq!(state.intensity, 0.0);
+                let until = match state.overridden.unwrap().until {
+                    Expiry::At(until) => until,
+                    other => panic!("{:?}", other),
+                };
+                let snooze = until.signed_duration_since(chrono::Local::now());
+                assert!(snooze > chrono::Duration::minutes(14) && snooze <= chrono::Duration::minutes(15), "{}", snooze);
+            },
+            other => panic!("{:?}", other),
+        }
+        assert!(!backend.is_grayscale());
+    }
+
+    #[test]
+    fn sends_transitions_to_subscribers() {
+        let (_directory, path, _server, _backend, _reloads) = running();
+        let mut subscriber = Client::connect(&path).unwrap();
+        assert_eq!(subscriber.request(&Request::Subscribe).unwrap(), Response::Ok);
+
+        let mut client = Client::connect(&path).unwrap();
+        client.request(&Request::SetOverride { on: Some(true), lasting: Lasting::NextBoundary }).unwrap();
+        client.request(&Request::Snooze { minutes: 5 }).unwrap();
+        let causes: Vec<(f64, Cause)> = (0..2).map(|_| match subscriber.receive().unwrap() {
+            Response::Transition(transition) => (transition.intensity, transition.cause),
+            other => panic!("{:?}", other),
+        }).collect();
+        assert_eq!(causes, vec![(1.0, Cause::Override), (0.0, Cause::Snooze)]);
+    }
+
+    #[test]
+    fn speaks_json_lines() {
+        let (_directory, path, _server, _backend, _reloads) = running();
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream.write_all(b"{\"version\":1,\"request\":\"set_override\",\"on\":false}\n").unwrap();
+        stream.write_all(b"{\"version\":1,\"request\":\"set_override\",\"on\":true,\"lasting\":{\"minutes\":30}}\n").unwrap();
+        stream.write_all(b"{\"version\":2,\"request\":\"get_state\"}\n").unwrap();
+        stream.write_all(b"{\"version\":1,\"request\":\"dance\"}\n").unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        assert_eq!(lines.next().unwrap().unwrap(), "{\"version\":1,\"response\":\"ok\"}");
+        assert_eq!(lines.next().unwrap().unwrap(), "{\"version\":1,\"response\":\"ok\"}");
+        assert!(lines.next().unwrap().unwrap().contains("protocol version 2 isn't supported"));
+        assert!(lines.next().unwrap().unwrap().starts_with("{\"version\":1,\"response\":\"error\""));
+    }
+
+    #[test]
+    fn replaces_a_stale_socket_and_cleans_up() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("goodnight.sock");
+        // left behind by an app that crashed
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let (commands, _receiver) = mpsc::channel();
+        let server = Server::start(&path, commands.clone(), Arc::new(|| Ok(()))).unwrap();
+        assert!(Client::connect(&path).is_ok());
+        // but not one that's still being listened on
+        assert!(Server::start(&path, commands, Arc::new(|| Ok(()))).is_err());
+        drop(server);
+        assert!(!path.exists());
+    }
+}
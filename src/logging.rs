@@ -0,0 +1,159 @@
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    panic,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use chrono::{DateTime, Local, SecondsFormat};
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// how big the log gets before it's moved aside for a new one
+const MAX_BYTES: u64 = 1024 * 1024;
+/// how many old logs are kept next to it, `.1` is the newest
+const KEEP: usize = 3;
+/// set to `debug` or `trace` to see more, or `warn` or `error` for less
+const LEVEL_VARIABLE: &str = "GOODNIGHT_LOG";
+
+/// shows what's going on in the terminal like it always did, and for the app
+/// also keeps it in a log file with the time and the level of each line
+pub struct Logger {
+    level: LevelFilter,
+    file: Option<Mutex<LogFile>>,
+}
+
+/// the log file, moved aside once it gets big
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+/// log from now on, to `file` as well if there is one, it's only logged to the terminal
+/// if the file can't be opened
+pub fn init(file: Option<&Path>) {
+    let level = env::var(LEVEL_VARIABLE).ok().and_then(|level| level.parse().ok()).unwrap_or(LevelFilter::Info);
+    let file = file.and_then(|path| match LogFile::open(path.to_owned()) {
+        Ok(file) => Some(Mutex::new(file)),
+        Err(err) => {
+            println!("can't write the log to {}: {}", path.display(), err);
+            None
+        },
+    });
+    if log::set_boxed_logger(Box::new(Logger { level, file })).is_ok() {
+        log::set_max_level(level);
+    }
+    // a crash is the thing most worth having in the log
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        log::error!("{}", info);
+        default_hook(info);
+    }));
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        println!("{}", record.args());
+        if let Some(file) = &self.file {
+            let mut file = file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            // there's nowhere left to say that logging went wrong
+            file.write(&line(&Local::now(), record)).ok();
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// one line of the log, like `2021-01-04T22:00:00.000+01:00 INFO transition: intensity=1.00 cause=schedule`
+fn line(time: &DateTime<Local>, record: &Record) -> String {
+    // our own modules are `goodnight::scheduler` and so on, the crate is the same everywhere
+    let target = record.target().rsplit("::").next().unwrap_or_default();
+    format!("{} {} {}: {}\n", time.to_rfc3339_opts(SecondsFormat::Millis, false), record.level(), target, record.args())
+}
+
+impl LogFile {
+    fn open(path: PathBuf) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path, file, size })
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > MAX_BYTES {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// `goodnight.log` becomes `goodnight.log.1`, that one `goodnight.log.2` and so on,
+    /// the oldest one is dropped
+    fn rotate(&mut self) -> io::Result<()> {
+        for number in (1..KEEP).rev() {
+            fs::rename(self.rotated(number), self.rotated(number + 1)).ok();
+        }
+        fs::rename(&self.path, self.rotated(1))?;
+        *self = Self::open(self.path.clone())?;
+        Ok(())
+    }
+
+    fn rotated(&self, number: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", number));
+        path.into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone;
+    use log::Level;
+    use super::*;
+
+    #[test]
+    fn writes_the_time_level_and_where_it_came_from() {
+        let time = Local.with_ymd_and_hms(2021, 1, 4, 22, 0, 0).unwrap() + chrono::Duration::milliseconds(250);
+        // the arguments only live as long as the statement
+        let line = line(&time, &Record::builder()
+            .level(Level::Info)
+            .target("goodnight::scheduler")
+            .args(format_args!("clock jumped by {} seconds", 60))
+            .build());
+        assert!(line.starts_with("2021-01-04T22:00:00.250"), "{}", line);
+        assert!(line.ends_with(" INFO scheduler: clock jumped by 60 seconds\n"), "{}", line);
+    }
+
+    #[test]
+    fn moves_big_logs_aside() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("data").join("goodnight.log");
+        let mut file = LogFile::open(path.clone()).unwrap();
+        let line = format!("{}\n", "z".repeat(1023));
+        for _ in 0..(KEEP + 2) * 1024 {
+            file.write(&line).unwrap();
+        }
+        let size = |path: &Path| fs::metadata(path).map(|metadata| metadata.len()).ok();
+        assert_eq!(size(&path), Some(MAX_BYTES));
+        for number in 1..=KEEP {
+            assert_eq!(size(&file.rotated(number)), Some(MAX_BYTES));
+        }
+        assert_eq!(size(&file.rotated(KEEP + 1)), None);
+
+        // and picks up where it left off
+        let mut file = LogFile::open(path.clone()).unwrap();
+        file.write(&line).unwrap();
+        assert_eq!(size(&path), Some(1024));
+    }
+}
@@ -1,71 +1,290 @@
 // this needs to be at the crate root
+#[cfg(target_os = "macos")]
 #[macro_use] extern crate cocoa;
+#[cfg(target_os = "macos")]
 #[macro_use] extern crate objc;
 
+mod cli;
+mod clock;
+mod color;
 mod config;
+mod instance;
+mod ipc;
+mod logging;
+mod restore;
+mod schedule;
+mod solar;
+mod state;
 mod timerange;
-mod grayscale;
+mod backend;
+mod scheduler;
+#[cfg(target_os = "macos")]
 mod tray;
 
 use directories::{ProjectDirs};
 use std::{
+    env,
     error::Error,
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+    process,
     thread,
-    time::Duration
+    time::Duration,
+    sync::{Arc, mpsc},
 };
-use confy::{load_path, store_path};
-use chrono::{Local, TimeZone};
-use crate::config::Config;
-use crate::grayscale::{is_grayscale, set_grayscale};
-use crate::tray::start_tray;
+use chrono::Local;
+use log::{debug, error, info, warn};
+use crate::cli::Action;
+use crate::clock::SystemClock;
+use crate::config::{Config, ConfigError};
+use crate::instance::{InstanceLock, LockError};
+use crate::restore::RestoreGuard;
+use crate::scheduler::{Command, Scheduler};
+use crate::state::StateFile;
+#[cfg(target_os = "macos")]
+use crate::tray::Tray;
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let project_dirs = ProjectDirs::from("", "",  "nighttime").unwrap();
-    let mut config_path = project_dirs.config_dir().to_owned();
+    let action = match cli::parse(env::args().skip(1)) {
+        Ok(action) => action,
+        Err(err) => {
+            println!("{}\n\n{}", err, cli::USAGE);
+            process::exit(2);
+        },
+    };
+    match action {
+        Action::Run => run(config_path(), false),
+        Action::Daemon => run(config_path(), true),
+        Action::Help => {
+            println!("{}", cli::USAGE);
+            Ok(())
+        },
+        action => {
+            // the commands only say what they did, there's nothing to keep
+            logging::init(None);
+            cli::run(action, &config_path(), &socket_path(), &lock_path())
+        },
+    }
+}
+
+fn project_dirs() -> ProjectDirs {
+    ProjectDirs::from("", "",  "nighttime").unwrap()
+}
+
+fn config_path() -> PathBuf {
+    let mut config_path = project_dirs().config_dir().to_owned();
     if cfg!(debug_assertions) {
         config_path.push("config.debug.yaml");
     } else {
         config_path.push("config.yaml");
     }
-    dbg!(&config_path);
-
-    let config: Config = load_path(&config_path).unwrap_or_else(|err| {
-        println!("error when parsing config file: {}", err);
-        println!("overwriting with default config");
-        let config = Config::default();
-        store_path(&config_path, &config)
-            .expect("can't write default configuration file");
-        config
-    });
-    dbg!(&config);
-    let nighttime = config.nighttime;
+    config_path
+}
+
+/// where what has to survive a restart is kept, including how to put the display back after a crash
+fn state_path() -> PathBuf {
+    let mut state_path = project_dirs().data_dir().to_owned();
+    if cfg!(debug_assertions) {
+        state_path.push("state.debug.yaml");
+    } else {
+        state_path.push("state.yaml");
+    }
+    state_path
+}
+
+/// where the running app listens for the command line and anything else that wants to control it
+fn socket_path() -> PathBuf {
+    let project_dirs = project_dirs();
+    // only linux has a runtime dir
+    let mut socket_path = project_dirs.runtime_dir().unwrap_or_else(|| project_dirs.cache_dir()).to_owned();
+    if cfg!(debug_assertions) {
+        socket_path.push("goodnight.debug.sock");
+    } else {
+        socket_path.push("goodnight.sock");
+    }
+    socket_path
+}
+
+/// held by the running app so there's only ever one of it
+fn lock_path() -> PathBuf {
+    socket_path().with_extension("lock")
+}
+
+/// where the app keeps what it did, with every change of the display and why,
+/// older logs are next to it
+fn log_path() -> PathBuf {
+    let mut log_path = project_dirs().data_dir().to_owned();
+    if cfg!(debug_assertions) {
+        log_path.push("goodnight.debug.log");
+    } else {
+        log_path.push("goodnight.log");
+    }
+    log_path
+}
+
+/// start the app and keep the display on schedule until it's quit,
+/// `headless` leaves out the tray and runs until it gets a signal to stop
+#[cfg_attr(not(target_os = "macos"), allow(unused_variables))]
+fn run(config_path: PathBuf, headless: bool) -> Result<(), Box<dyn Error>> {
+    // two of us would switch the display back and forth and each put it back their own way,
+    // the lock goes away with us however we stop
+    let lock = InstanceLock::acquire(&lock_path());
+    if let Err(err @ LockError::Running(_)) = &lock {
+        // before the log is opened, it's the running one's
+        println!("{}, `goodnight status` and the other commands talk to it, see `goodnight help`", err);
+        process::exit(1);
+    }
+    logging::init(Some(&log_path()));
+    info!("starting goodnight {} with pid {}", env!("CARGO_PKG_VERSION"), process::id());
+    let _lock = lock.map_err(|err| warn!("{}, starting anyway", err)).ok();
+    debug!("config file: {}", config_path.display());
+
+    // a config with a typo in it is the user's to fix, so it's never overwritten,
+    // we run on the defaults until it loads
+    #[cfg_attr(not(target_os = "macos"), allow(unused_variables))]
+    let (config, config_error) = match config::load(&config_path) {
+        Ok(config) => (config, None),
+        Err(err) => {
+            error!("error in config file, running on the default config until it's fixed: {}", err);
+            match config::back_up(&config_path) {
+                Ok(backup) => info!("saved a copy of the broken config to {}", backup.display()),
+                Err(err) => warn!("can't save a copy of the broken config: {}", err),
+            }
+            (Config::default(), Some(err.to_string()))
+        },
+    };
+    debug!("config: {:?}", config);
+    let nighttime = config.schedule();
+    if nighttime.is_solar() && config.location.is_none() {
+        warn!("sunrise and sunset need a location in the config, ranges using them are left out");
+    }
+    info!("tonight: {}", nighttime.tonight(&Local::now()));
     let loop_frequency = Duration::from_secs(config.loop_seconds);
+    let backend = backend::select(config.effect.clone()).map_err(|err| {
+        error!("can't switch the display: {}", err);
+        err
+    })?;
+    info!("display backend: {}", backend.name());
     // check if the screen is already in grayscale or not to revert to the
-    // original setting when quitting the app if it wasn't toggled manually
-    let was_grayscale = is_grayscale();
-
-    thread::spawn(move || {
-        // don't reset manually set grayscale but only until next night time boundary
-        // e.g. if you turn on grayscale earlier than nighttime starts we still turn it off in the morning
-        // and if you turn off grayscale manually early in the morning we still turn it on at night
-        // this should also account for cases when the previous loop iteration was the same time period as the current one
-        // but we did cross the night time boundary in the real time, e.g. when laptop was asleep the whole day
-        let mut previous = Local.timestamp(0, 0);
-
-        loop {
-            let now = Local::now();
-            if nighttime.did_cross_boundary(previous, now) {
-                let is_nighttime = nighttime.includes(Local::now().time());
-                if is_nighttime != is_grayscale() {
-                    set_grayscale(is_nighttime);
-                }
+    // original setting when quitting the app if it wasn't toggled manually,
+    // or if the last run crashed how it was before that
+    let state = StateFile::open(state_path());
+    let guard = Arc::new(RestoreGuard::take(Arc::clone(&backend), state.clone()));
+
+    let fade = Duration::from_secs(config.fade_minutes * 60);
+    // the scheduler sleeps until the next boundary, waking up at least every loop_seconds
+    // to notice the wall clock jumping after a suspend or a timezone change
+    let mut scheduler = Scheduler::new(nighttime, Arc::clone(&backend), SystemClock, fade, loop_frequency);
+    scheduler.persist_to(state);
+    // turning it on or off by hand until the schedule switches or for good, from the tray
+    // or the command line, is how it stays when we stop
+    scheduler.keep_overrides(Arc::clone(&guard));
+    let (commands, receiver) = mpsc::channel();
+    let (stopped, scheduler_stopped) = mpsc::channel::<()>();
+    let crash_guard = Arc::clone(&guard);
+    let scheduler_thread = thread::spawn(move || {
+        // with the tray the main thread is busy running the app, so a crash is cleaned up here
+        if panic::catch_unwind(AssertUnwindSafe(|| scheduler.run(receiver))).is_err() {
+            crash_guard.restore();
+            error!("the scheduler crashed, the display is back the way it was");
+            process::exit(1);
+        }
+        drop(stopped);
+    });
+
+    // there's only a tray on macos
+    #[cfg(target_os = "macos")]
+    let tray = if headless {
+        None
+    } else {
+        let tray = Arc::new(Tray::new(config_path.clone(), &config, Arc::clone(&backend), Arc::clone(&guard), commands.clone()));
+        tray.set_error(config_error.as_deref());
+        Some(tray)
+    };
+
+    // pick up changes to the config without a restart, one that doesn't parse is ignored,
+    // only the effect needs a restart since the backend is set up for it
+    let reload_commands = commands.clone();
+    let effect = config.effect.clone();
+    #[cfg(target_os = "macos")]
+    let reload_tray = tray.clone();
+    let on_reload = Arc::new(move |reloaded: Result<Config, ConfigError>| match reloaded {
+        Ok(config) => {
+            let nighttime = config.schedule();
+            info!("config changed, tonight: {}", nighttime.tonight(&Local::now()));
+            reload_commands.send(Command::Reload(Box::new(nighttime))).ok();
+            reload_commands.send(Command::SetMaxSleep(Duration::from_secs(config.loop_seconds))).ok();
+            reload_commands.send(Command::SetFade(Duration::from_secs(config.fade_minutes * 60))).ok();
+            let restart = (config.effect != effect).then_some("the new effect shows after a restart");
+            if let Some(restart) = restart {
+                warn!("{}", restart);
+            }
+            #[cfg(target_os = "macos")]
+            if let Some(tray) = &reload_tray {
+                tray.update(&config);
+                tray.set_error(restart);
             }
-            previous = now;
-            thread::sleep(loop_frequency);
+        },
+        Err(err) => {
+            error!("error in config file, keeping the previous one: {}", err);
+            #[cfg(target_os = "macos")]
+            if let Some(tray) = &reload_tray {
+                tray.set_error(Some(&err.to_string()));
+            }
+        },
+    });
+    let watch_reload = Arc::clone(&on_reload);
+    let watcher = config::watch(config_path.clone(), move |reloaded| watch_reload(reloaded));
+    if let Err(err) = &watcher {
+        warn!("can't watch the config file, changes need a restart: {}", err);
+    }
+
+    // the command line and anything else can control us through the socket
+    let reload: ipc::Reload = Arc::new(move || {
+        let reloaded = config::load(&config_path);
+        let result = reloaded.as_ref().map(|_| ()).map_err(ToString::to_string);
+        on_reload(reloaded);
+        result
+    });
+    let socket_path = socket_path();
+    let server = ipc::Server::start(&socket_path, commands.clone(), reload);
+    if let Err(err) = &server {
+        warn!("can't listen for commands on {}: {}", socket_path.display(), err);
+    }
+
+    #[cfg(target_os = "macos")]
+    let has_tray = tray.is_some();
+    #[cfg(not(target_os = "macos"))]
+    let has_tray = false;
+
+    // ctrl-c, SIGTERM from a service manager and SIGHUP when the terminal goes away
+    let signal_commands = commands.clone();
+    let signal_guard = Arc::clone(&guard);
+    let handled = ctrlc::set_handler(move || {
+        info!("got a signal to stop");
+        signal_commands.send(Command::Stop).ok();
+        if has_tray {
+            // the main thread won't get to it, so put the display back from here
+            // once the scheduler is done and can't switch it again
+            scheduler_stopped.recv_timeout(Duration::from_secs(1)).ok();
+            signal_guard.restore();
+            process::exit(0);
         }
     });
+    if let Err(err) = handled {
+        warn!("can't catch signals, being killed will leave the display as it is: {}", err);
+    }
+
+    #[cfg(target_os = "macos")]
+    if has_tray {
+        // quitting exits the process from inside appkit, the tray restores the display just before
+        tray::run();
+    }
 
-    start_tray(config_path, &config, was_grayscale);
+    scheduler_thread.join().ok();
+    // stop the scheduler first so it can't switch the display back after restoring it
+    guard.restore();
+    info!("stopped, the display is back the way it was");
 
     Ok(())
 }
@@ -0,0 +1,152 @@
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use log::{info, warn};
+use crate::backend::DisplayBackend;
+use crate::scheduler::Cause;
+use crate::state::StateFile;
+
+/// puts the display back the way it was before we started, once, however we stop
+///
+/// while we're running the state file says how to put it back,
+/// so after a crash that didn't leave time for it the next run can do it instead
+pub struct RestoreGuard {
+    backend: Arc<dyn DisplayBackend>,
+    /// how it was before we started
+    original: bool,
+    was_grayscale: AtomicBool,
+    state: StateFile,
+    restored: AtomicBool,
+}
+
+impl RestoreGuard {
+    /// remember how the display is now, or how it was before a run that crashed
+    /// and put it back that way, then keep that in `state` until we're done
+    pub fn take(backend: Arc<dyn DisplayBackend>, state: StateFile) -> Self {
+        let was_grayscale = match state.get().was_grayscale {
+            Some(was_grayscale) => {
+                warn!("the last run didn't get to put the display back, doing that now");
+                backend.restore(was_grayscale);
+                log_restore(was_grayscale);
+                was_grayscale
+            },
+            None => backend.is_grayscale(),
+        };
+        let guard = Self { backend, original: was_grayscale, was_grayscale: AtomicBool::new(was_grayscale), state, restored: AtomicBool::new(false) };
+        guard.mark();
+        guard
+    }
+
+    /// leave the display like this when we stop, e.g. after toggling it by hand
+    pub fn keep(&self, grayscale: bool) {
+        self.was_grayscale.store(grayscale, Ordering::SeqCst);
+        self.mark();
+    }
+
+    /// put the display back the way it was before we started after all
+    pub fn reset(&self) {
+        self.keep(self.original);
+    }
+
+    fn mark(&self) {
+        // the scheduler might still be winding down, once it's put back there's nothing left to keep
+        if self.restored.load(Ordering::SeqCst) {
+            return;
+        }
+        let was_grayscale = self.was_grayscale.load(Ordering::SeqCst);
+        self.state.update(|state| state.was_grayscale = Some(was_grayscale));
+    }
+
+    /// put the display back, only the first time this is called,
+    /// the scheduler should be stopped by then so it can't switch it again
+    pub fn restore(&self) {
+        if self.restored.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let was_grayscale = self.was_grayscale.load(Ordering::SeqCst);
+        self.backend.restore(was_grayscale);
+        log_restore(was_grayscale);
+        self.state.update(|state| state.was_grayscale = None);
+    }
+}
+
+/// in the same shape as the scheduler's transitions so the log tells the whole story
+fn log_restore(grayscale: bool) {
+    info!(target: "transition", "intensity={:.2} cause={}", if grayscale { 1.0 } else { 0.0 }, Cause::Restore);
+}
+
+impl Drop for RestoreGuard {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+    use super::*;
+    use crate::backend::RecordingBackend;
+    use crate::state::RuntimeState;
+
+    fn state() -> (tempfile::TempDir, PathBuf) {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("data").join("state.yaml");
+        (directory, path)
+    }
+
+    fn is_marked(path: &Path) -> bool {
+        RuntimeState::load(path).was_grayscale.is_some()
+    }
+
+    #[test]
+    fn restores_once_and_cleans_up() {
+        let (_directory, path) = state();
+        let backend = Arc::new(RecordingBackend::new(false));
+        let guard = RestoreGuard::take(backend.clone(), StateFile::open(path.clone()));
+        assert!(is_marked(&path));
+        backend.set_grayscale(true);
+        guard.restore();
+        drop(guard);
+        assert_eq!(backend.take_switches(), vec![true, false]);
+        assert!(!is_marked(&path));
+    }
+
+    #[test]
+    fn restores_when_dropped() {
+        let (_directory, path) = state();
+        let backend = Arc::new(RecordingBackend::new(true));
+        let guard = RestoreGuard::take(backend.clone(), StateFile::open(path));
+        backend.set_grayscale(false);
+        drop(guard);
+        assert!(backend.is_grayscale());
+    }
+
+    #[test]
+    fn repairs_what_a_crash_left_behind() {
+        let (_directory, path) = state();
+        let backend = Arc::new(RecordingBackend::new(false));
+        let guard = RestoreGuard::take(backend.clone(), StateFile::open(path.clone()));
+        backend.set_grayscale(true);
+        // a crash doesn't run anything
+        std::mem::forget(guard);
+        assert!(is_marked(&path));
+
+        let guard = RestoreGuard::take(backend.clone(), StateFile::open(path.clone()));
+        assert!(!backend.is_grayscale());
+        // and what it puts back in the end is how it was before the crash
+        backend.set_grayscale(true);
+        drop(guard);
+        assert!(!backend.is_grayscale());
+        assert!(!is_marked(&path));
+    }
+
+    #[test]
+    fn keeps_a_manual_toggle() {
+        let (_directory, path) = state();
+        let backend = Arc::new(RecordingBackend::new(false));
+        let guard = RestoreGuard::take(backend.clone(), StateFile::open(path.clone()));
+        backend.set_grayscale(true);
+        guard.keep(true);
+        std::mem::forget(guard);
+        RestoreGuard::take(backend.clone(), StateFile::open(path)).restore();
+        assert!(backend.is_grayscale());
+    }
+}
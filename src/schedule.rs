@@ -0,0 +1,691 @@
+use std::fmt;
+use serde::{
+    Serialize, Deserialize, Deserializer,
+    de::{MapAccess, SeqAccess, Visitor, value::{MapAccessDeserializer, SeqAccessDeserializer}},
+};
+use chrono::{
+    Datelike, DateTime, Duration, LocalResult, NaiveDate, NaiveDateTime, NaiveTime, Offset, TimeZone, Timelike,
+    Weekday,
+};
+
+use crate::solar::Location;
+use crate::timerange::{Boundary, TimeRange};
+
+/// something for each day of the week, days can be left out
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(bound(deserialize = "T: Deserialize<'de>"))]
+pub struct Weekdays<T> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mon: Option<T>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tue: Option<T>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wed: Option<T>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thu: Option<T>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fri: Option<T>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sat: Option<T>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sun: Option<T>,
+}
+
+impl<T> Weekdays<T> {
+    pub fn get(&self, weekday: Weekday) -> Option<&T> {
+        match weekday {
+            Weekday::Mon => self.mon.as_ref(),
+            Weekday::Tue => self.tue.as_ref(),
+            Weekday::Wed => self.wed.as_ref(),
+            Weekday::Thu => self.thu.as_ref(),
+            Weekday::Fri => self.fri.as_ref(),
+            Weekday::Sat => self.sat.as_ref(),
+            Weekday::Sun => self.sun.as_ref(),
+        }
+    }
+}
+
+impl<T> Default for Weekdays<T> {
+    fn default() -> Self {
+        Self { mon: None, tue: None, wed: None, thu: None, fri: None, sat: None, sun: None }
+    }
+}
+
+const WEEK: [Weekday; 7] = [
+    Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri, Weekday::Sat, Weekday::Sun,
+];
+
+/// several nighttime ranges that repeat every day,
+/// where they overlap or touch they count as one
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(into = "OneOrMore")]
+pub struct Schedule(Vec<TimeRange<Boundary>>);
+
+/// lets the config have just one range without making it a list
+#[derive(Serialize)]
+#[serde(untagged)]
+enum OneOrMore {
+    One(TimeRange<Boundary>),
+    More(Vec<TimeRange<Boundary>>),
+}
+
+/// reading it back is done by hand, an untagged enum would try one variant
+/// after the other and lose what went wrong where in the file
+impl<'de> Deserialize<'de> for Schedule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct OneOrMoreVisitor;
+
+        impl<'de> Visitor<'de> for OneOrMoreVisitor {
+            type Value = Schedule;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a time range or a list of them")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Schedule, A::Error> {
+                TimeRange::<Boundary>::deserialize(MapAccessDeserializer::new(map)).map(Schedule::from)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Schedule, A::Error> {
+                Vec::deserialize(SeqAccessDeserializer::new(seq)).map(Schedule)
+            }
+        }
+
+        deserializer.deserialize_any(OneOrMoreVisitor)
+    }
+}
+
+impl From<Schedule> for OneOrMore {
+    fn from(schedule: Schedule) -> Self {
+        match schedule.0.as_slice() {
+            [range] => OneOrMore::One(*range),
+            _ => OneOrMore::More(schedule.0),
+        }
+    }
+}
+
+impl From<TimeRange> for Schedule {
+    fn from(range: TimeRange) -> Self {
+        Self(vec![range.into()])
+    }
+}
+
+impl From<TimeRange<Boundary>> for Schedule {
+    fn from(range: TimeRange<Boundary>) -> Self {
+        Self(vec![range])
+    }
+}
+
+impl<R: Into<TimeRange<Boundary>>> From<Vec<R>> for Schedule {
+    fn from(ranges: Vec<R>) -> Self {
+        Self(ranges.into_iter().map(Into::into).collect())
+    }
+}
+
+impl Schedule {
+    pub fn ranges(&self) -> &[TimeRange<Boundary>] {
+        &self.0
+    }
+
+    /// check if any of the ranges start or end relative to the sun
+    pub fn is_solar(&self) -> bool {
+        self.0.iter().any(|range| range.start().is_solar() || range.end().is_solar())
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "none");
+        }
+        let ranges: Vec<String> = self.0.iter().map(|range| range.to_string()).collect();
+        write!(f, "{}", ranges.join(" "))
+    }
+}
+
+/// whether it's daytime or nighttime or somewhere in between
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Day,
+    /// nighttime is about to start, with how far along the fade is from 0 to 1
+    FadingIn(f64),
+    Night,
+    /// nighttime just ended, with how strong the effect still is from 1 to 0
+    FadingOut(f64),
+}
+
+impl Phase {
+    /// how strong the nighttime effect should be, from 0 during the day to 1 during nighttime
+    pub fn intensity(self) -> f64 {
+        match self {
+            Self::Day => 0.0,
+            Self::FadingIn(intensity) | Self::FadingOut(intensity) => intensity,
+            Self::Night => 1.0,
+        }
+    }
+
+    pub fn is_fading(self) -> bool {
+        matches!(self, Self::FadingIn(_) | Self::FadingOut(_))
+    }
+}
+
+/// nighttime that can be different depending on the day of the week
+///
+/// a day's night goes from noon until noon the next day, so a range starting
+/// in the morning belongs to the evening before, e.g. friday's 01:00-10:00 is
+/// for going to bed late after friday evening and starts on saturday.
+/// when one night runs into the next they count as one long nighttime.
+/// ranges relative to sunrise or sunset need a `location`, without one
+/// or on days the sun doesn't set they're left out
+#[derive(Debug, Clone)]
+pub struct WeeklySchedule {
+    default: Schedule,
+    weekdays: Weekdays<Schedule>,
+    location: Option<Location>,
+}
+
+impl WeeklySchedule {
+    pub fn new(default: Schedule, weekdays: Weekdays<Schedule>, location: Option<Location>) -> Self {
+        Self { default, weekdays, location }
+    }
+
+    /// return the ranges for the night after `weekday`
+    pub fn for_weekday(&self, weekday: Weekday) -> &Schedule {
+        self.weekdays.get(weekday).unwrap_or(&self.default)
+    }
+
+    /// nights after `days` days beginning with `first`, as start and end instants
+    fn nights<Tz: TimeZone>(&self, tz: &Tz, first: NaiveDate, days: i64) -> Vec<(DateTime<Tz>, DateTime<Tz>)> {
+        (0..days)
+            .map(|day| first + Duration::days(day))
+            .flat_map(|date| self.for_weekday(date.weekday()).ranges().iter().map(move |range| (date, *range)))
+            .filter_map(|(date, range)| {
+                let (start, end) = range.on(date, tz, self.location)?;
+                Some((localize(tz, &start), localize(tz, &end)))
+            })
+            .collect()
+    }
+
+    /// check if given `time` is within nighttime
+    pub fn includes<Tz: TimeZone>(&self, time: &DateTime<Tz>) -> bool {
+        // a night starts within a day or so after its date and ends within two days after that,
+        // so one from a few days ago could in theory still be going
+        let nights = self.nights(&time.timezone(), time.naive_local().date() - Duration::days(4), 5);
+        includes(&nights, time)
+    }
+
+    /// moments around `time` when nighttime starts or ends, in order
+    fn boundaries_around<Tz: TimeZone>(&self, time: &DateTime<Tz>) -> Vec<DateTime<Tz>> {
+        // a week and a bit is enough to see every weekday's ranges at least once
+        let (first, days) = (time.naive_local().date() - Duration::days(4), 12);
+        let nights = self.nights(&time.timezone(), first, days);
+        // nights before the ones we have are over by the day before `time`, and nights after
+        // them can start up to half a day before their date's noon, boundaries outside
+        // of that could still turn out to be inside one of them
+        let floor = localize(&time.timezone(), &(first + Duration::days(3)).and_hms_opt(0, 0, 0).unwrap());
+        let horizon = localize(&time.timezone(), &(first + Duration::days(days - 1)).and_time(noon()));
+        let mut boundaries: Vec<DateTime<Tz>> = nights.iter()
+            .flat_map(|(start, end)| vec![start.clone(), end.clone()])
+            .filter(|boundary| boundary >= &floor && boundary < &horizon)
+            .collect();
+        boundaries.sort();
+        boundaries.dedup();
+        // nights that overlap or touch are one nighttime, so skip boundaries inside it
+        boundaries.into_iter().filter(|boundary| includes(&nights, boundary) != includes_until(&nights, boundary)).collect()
+    }
+
+    /// return the first moment after `time` when nighttime starts or ends,
+    /// if nighttime never starts or ends there isn't one
+    pub fn next_boundary_after<Tz: TimeZone>(&self, time: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+        self.boundaries_around(time).into_iter().find(|boundary| boundary > time)
+    }
+
+    /// return the last moment up to `time` when nighttime started or ended,
+    /// looking back a day at least
+    pub fn previous_boundary_until<Tz: TimeZone>(&self, time: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+        self.boundaries_around(time).into_iter().rev().find(|boundary| boundary <= time)
+    }
+
+    /// where `time` is between daytime and nighttime when fading in over `fade`
+    /// before nighttime starts and fading out over `fade` after it ends
+    pub fn phase<Tz: TimeZone>(&self, time: &DateTime<Tz>, fade: Duration) -> Phase {
+        if self.includes(time) {
+            return Phase::Night;
+        }
+        if fade <= Duration::zero() {
+            return Phase::Day;
+        }
+        let progress = |distance: Duration| 1.0 - distance.num_milliseconds() as f64 / fade.num_milliseconds() as f64;
+        // it's daytime so the next boundary is a start and the previous one an end
+        let until_start = self.next_boundary_after(time).map(|start| start - time.clone());
+        let since_end = self.previous_boundary_until(time).map(|end| time.clone() - end);
+        match (until_start, since_end) {
+            (Some(until_start), since_end) if until_start < fade && !matches!(since_end, Some(since_end) if since_end < until_start) => {
+                Phase::FadingIn(progress(until_start))
+            },
+            (_, Some(since_end)) if since_end < fade => Phase::FadingOut(progress(since_end)),
+            _ => Phase::Day,
+        }
+    }
+
+    /// check if nighttime started or ended after `since` and up to `until`
+    ///
+    /// if the timezone changed in between, e.g. when travelling, times of day mean something else now,
+    /// so switching timezones counts as a boundary if it turns nighttime on or off by itself
+    pub fn did_cross_boundary<Tz: TimeZone>(&self, since: DateTime<Tz>, until: DateTime<Tz>) -> bool {
+        let moved = since.with_timezone(&until.timezone());
+        if moved.offset().fix() != since.offset().fix() {
+            // the timezone type might only know about the current rules, like `Local`,
+            // so keep the offset `since` had to tell what time it was before
+            let before = since.with_timezone(&since.offset().fix());
+            if self.includes(&before) != self.includes(&moved) {
+                return true;
+            }
+        }
+        matches!(self.next_boundary_after(&moved), Some(boundary) if boundary <= until)
+    }
+
+    /// check if any day has ranges relative to the sun
+    pub fn is_solar(&self) -> bool {
+        WEEK.iter().any(|weekday| self.for_weekday(*weekday).is_solar())
+    }
+
+    /// the ranges for the night after `date` with sunrise and sunset worked out,
+    /// ranges that don't happen that night are left out
+    pub fn on<Tz: TimeZone>(&self, date: NaiveDate, tz: &Tz) -> Schedule {
+        let ranges: Vec<TimeRange> = self.for_weekday(date.weekday()).ranges().iter()
+            .filter_map(|range| range.resolve(date, tz, self.location))
+            .collect();
+        ranges.into()
+    }
+
+    /// the ranges for the night that `now` belongs to, before noon that's last night
+    pub fn tonight<Tz: TimeZone>(&self, now: &DateTime<Tz>) -> Schedule {
+        let date = (now.clone() - Duration::hours(12)).naive_local().date();
+        self.on(date, &now.timezone())
+    }
+}
+
+fn noon() -> NaiveTime {
+    NaiveTime::from_hms_opt(12, 0, 0).unwrap()
+}
+
+/// turn a local time into an instant
+///
+/// a time that gets skipped when the clocks go forward happens as soon as the clocks
+/// have gone past it, a time that happens twice when they go back counts the first time
+fn localize<Tz: TimeZone>(tz: &Tz, time: &NaiveDateTime) -> DateTime<Tz> {
+    match tz.from_local_datetime(time) {
+        LocalResult::Single(instant) => instant,
+        LocalResult::Ambiguous(earliest, _) => earliest,
+        LocalResult::None => {
+            // clocks change on whole minutes and never skip more than a day,
+            // so the first minute that exists after the gap is when they changed
+            let minute = time.date().and_hms_opt(time.hour(), time.minute(), 0).unwrap();
+            (1..=24 * 60)
+                .find_map(|minutes| tz.from_local_datetime(&(minute + Duration::minutes(minutes))).earliest())
+                .unwrap_or_else(|| tz.from_utc_datetime(time))
+        },
+    }
+}
+
+/// nights include their start but not their end
+fn includes<Tz: TimeZone>(nights: &[(DateTime<Tz>, DateTime<Tz>)], time: &DateTime<Tz>) -> bool {
+    nights.iter().any(|(start, end)| start <= time && time < end)
+}
+
+/// check if the moment right before `time` was within one of the `nights`
+fn includes_until<Tz: TimeZone>(nights: &[(DateTime<Tz>, DateTime<Tz>)], time: &DateTime<Tz>) -> bool {
+    nights.iter().any(|(start, end)| start < time && time <= end)
+}
+
+impl fmt::Display for WeeklySchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.default)?;
+        for weekday in WEEK.iter() {
+            if let Some(range) = self.weekdays.get(*weekday) {
+                write!(f, ", {} {}", weekday.to_string().to_lowercase(), range)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+    use chrono_tz::{Tz, America::New_York, Europe::{Berlin, London}};
+
+    use super::*;
+
+    /// 2021-01-04 is a monday
+    fn time(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(&format!("2021-01-{}:00-00:00", s)).unwrap().with_timezone(&Utc)
+    }
+
+    fn weekend() -> WeeklySchedule {
+        WeeklySchedule::new(TimeRange::from_hmhm(23, 0, 7, 0).into(), Weekdays {
+            fri: Some(TimeRange::from_hmhm(1, 0, 10, 0).into()),
+            sat: Some(TimeRange::from_hmhm(1, 0, 10, 0).into()),
+            ..Weekdays::default()
+        }, None)
+    }
+
+    #[test]
+    fn weekday_default() {
+        let schedule = weekend();
+        assert_eq!(schedule.for_weekday(Weekday::Wed).to_string(), "23:00-07:00");
+        assert_eq!(schedule.for_weekday(Weekday::Sat).to_string(), "01:00-10:00");
+        assert!(schedule.includes(&time("06T23:30")));
+        assert!(schedule.includes(&time("07T06:59")));
+        assert!(!schedule.includes(&time("07T07:00")));
+        assert!(!schedule.includes(&time("06T22:59")));
+    }
+
+    #[test]
+    fn start_is_included_end_is_not() {
+        let schedule = weekend();
+        assert!(schedule.includes(&time("06T23:00")));
+        assert!(!schedule.includes(&time("07T07:00")));
+        assert_eq!(schedule.next_boundary_after(&time("06T22:00")), Some(time("06T23:00")));
+        assert_eq!(schedule.next_boundary_after(&time("06T23:00")), Some(time("07T07:00")));
+    }
+
+    #[test]
+    fn morning_start_belongs_to_the_night_before() {
+        let schedule = weekend();
+        // thursday night ends on friday morning
+        assert!(schedule.includes(&time("08T06:00")));
+        assert!(!schedule.includes(&time("08T08:00")));
+        // friday night starts late on saturday
+        assert!(!schedule.includes(&time("08T23:30")));
+        assert!(schedule.includes(&time("09T01:30")));
+        assert!(schedule.includes(&time("09T09:30")));
+        // so does saturday night on sunday
+        assert!(!schedule.includes(&time("09T23:30")));
+        assert!(schedule.includes(&time("10T09:30")));
+        // sunday night is back to the default
+        assert!(schedule.includes(&time("10T23:30")));
+        assert!(schedule.includes(&time("11T06:30")));
+        assert!(!schedule.includes(&time("11T08:00")));
+    }
+
+    #[test]
+    fn boundaries_over_a_weekend() {
+        let schedule = weekend();
+        let mut boundaries = Vec::new();
+        let mut now = time("08T12:00");
+        while let Some(boundary) = schedule.next_boundary_after(&now) {
+            if boundary > time("11T12:00") {
+                break;
+            }
+            boundaries.push(boundary.format("%a %H:%M").to_string());
+            now = boundary;
+        }
+        assert_eq!(boundaries, vec!["Sat 01:00", "Sat 10:00", "Sun 01:00", "Sun 10:00", "Sun 23:00", "Mon 07:00"]);
+    }
+
+    #[test]
+    fn overlapping_nights_are_one_nighttime() {
+        let schedule = WeeklySchedule::new(TimeRange::from_hmhm(12, 0, 14, 0).into(), Weekdays {
+            mon: Some(TimeRange::from_hmhm(22, 0, 13, 0).into()),
+            ..Weekdays::default()
+        }, None);
+        assert_eq!(schedule.next_boundary_after(&time("04T21:00")), Some(time("04T22:00")));
+        assert_eq!(schedule.next_boundary_after(&time("04T22:00")), Some(time("05T14:00")));
+        assert!(!schedule.did_cross_boundary(time("05T11:59"), time("05T12:01")));
+        assert!(!schedule.did_cross_boundary(time("05T12:59"), time("05T13:01")));
+        assert!(schedule.did_cross_boundary(time("05T13:59"), time("05T14:01")));
+    }
+
+    #[test]
+    fn nights_that_touch_are_one_nighttime() {
+        let schedule = WeeklySchedule::new(TimeRange::from_hmhm(12, 0, 14, 0).into(), Weekdays {
+            mon: Some(TimeRange::from_hmhm(22, 0, 12, 0).into()),
+            ..Weekdays::default()
+        }, None);
+        assert_eq!(schedule.next_boundary_after(&time("04T22:00")), Some(time("05T14:00")));
+    }
+
+    #[test]
+    fn all_day_every_day_has_no_boundaries() {
+        let schedule = WeeklySchedule::new(TimeRange::from_hmhm(12, 0, 12, 0).into(), Weekdays::default(), None);
+        assert!(schedule.includes(&time("04T12:00")));
+        assert!(schedule.includes(&time("04T11:59")));
+        assert_eq!(schedule.next_boundary_after(&time("04T12:00")), None);
+        assert!(!schedule.did_cross_boundary(time("04T12:00"), time("20T12:00")));
+    }
+
+    #[test]
+    fn backwards_is_not_crossing() {
+        let schedule = weekend();
+        assert!(!schedule.did_cross_boundary(time("05T12:00"), time("04T12:00")));
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(weekend().to_string(), "23:00-07:00, fri 01:00-10:00, sat 01:00-10:00");
+        assert_eq!(nap_and_night().to_string(), "13:00-14:00 23:00-07:00");
+        assert_eq!(Schedule::default().to_string(), "none");
+    }
+
+    fn nap_and_night() -> Schedule {
+        vec![TimeRange::from_hmhm(13, 0, 14, 0), TimeRange::from_hmhm(23, 0, 7, 0)].into()
+    }
+
+    /// the same ranges every night
+    fn nightly(schedule: Schedule) -> WeeklySchedule {
+        WeeklySchedule::new(schedule, Weekdays::default(), None)
+    }
+
+    #[test]
+    fn several_ranges_a_day() {
+        let schedule = nightly(nap_and_night());
+        assert!(schedule.includes(&time("04T13:30")));
+        assert!(!schedule.includes(&time("04T14:00")));
+        assert!(schedule.includes(&time("04T23:30")));
+        assert!(schedule.includes(&time("05T06:30")));
+        assert!(!schedule.includes(&time("05T12:00")));
+        assert_eq!(schedule.next_boundary_after(&time("04T08:00")), Some(time("04T13:00")));
+        assert_eq!(schedule.next_boundary_after(&time("04T13:00")), Some(time("04T14:00")));
+        assert_eq!(schedule.next_boundary_after(&time("04T14:00")), Some(time("04T23:00")));
+        assert_eq!(schedule.next_boundary_after(&time("04T23:00")), Some(time("05T07:00")));
+        assert!(schedule.did_cross_boundary(time("04T12:59"), time("04T13:00")));
+        assert!(!schedule.did_cross_boundary(time("04T14:00"), time("04T22:59")));
+    }
+
+    #[test]
+    fn overlapping_ranges_are_one_nighttime() {
+        let schedule = nightly(vec![TimeRange::from_hmhm(22, 0, 2, 0), TimeRange::from_hmhm(1, 0, 7, 0)].into());
+        assert!(schedule.includes(&time("05T01:30")));
+        assert_eq!(schedule.next_boundary_after(&time("04T22:00")), Some(time("05T07:00")));
+        assert!(!schedule.did_cross_boundary(time("05T00:59"), time("05T02:01")));
+    }
+
+    #[test]
+    fn adjacent_ranges_are_one_nighttime() {
+        let schedule = nightly(vec![TimeRange::from_hmhm(14, 0, 15, 0), TimeRange::from_hmhm(13, 0, 14, 0)].into());
+        assert!(schedule.includes(&time("04T14:00")));
+        assert_eq!(schedule.next_boundary_after(&time("04T13:00")), Some(time("04T15:00")));
+        assert!(!schedule.did_cross_boundary(time("04T13:59"), time("04T14:01")));
+    }
+
+    #[test]
+    fn nested_ranges_are_one_nighttime() {
+        let schedule = nightly(vec![TimeRange::from_hmhm(21, 0, 8, 0), TimeRange::from_hmhm(23, 0, 7, 0)].into());
+        assert_eq!(schedule.next_boundary_after(&time("04T12:00")), Some(time("04T21:00")));
+        assert_eq!(schedule.next_boundary_after(&time("04T21:00")), Some(time("05T08:00")));
+    }
+
+    #[test]
+    fn no_ranges_is_never_nighttime() {
+        let schedule = nightly(Schedule::default());
+        assert!(!schedule.includes(&time("04T00:00")));
+        assert_eq!(schedule.next_boundary_after(&time("04T00:00")), None);
+    }
+
+    #[test]
+    fn parses_one_range_or_several() {
+        let one: Schedule = serde_yaml::from_str("start: \"23:00:00\"\nend: \"07:00:00\"\n").unwrap();
+        assert_eq!(one.to_string(), "23:00-07:00");
+        let several: Schedule = serde_yaml::from_str(&serde_yaml::to_string(&nap_and_night()).unwrap()).unwrap();
+        assert_eq!(several.to_string(), "13:00-14:00 23:00-07:00");
+    }
+
+    fn solar(latitude: f64, longitude: f64) -> WeeklySchedule {
+        let range: TimeRange<Boundary> = TimeRange::new("sunset".parse().unwrap(), "sunrise".parse().unwrap());
+        WeeklySchedule::new(range.into(), Weekdays::default(), Some(Location { latitude, longitude }))
+    }
+
+    fn date(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap()
+    }
+
+    #[test]
+    fn sunset_to_sunrise_changes_every_day() {
+        let schedule = solar(52.52, 13.405);
+        assert_eq!(schedule.on(date("2021-06-21"), &Utc).to_string(), "19:33-02:43");
+        assert_eq!(schedule.on(date("2021-12-21"), &Utc).to_string(), "14:53-07:15");
+    }
+
+    #[test]
+    fn polar_night_is_dark_all_day() {
+        // tromsø, the sun gets closest to rising at about 10:42 utc
+        let schedule = solar(69.65, 18.96);
+        let december = Utc.with_ymd_and_hms(2021, 12, 20, 0, 0, 0).unwrap();
+        assert!(schedule.includes(&(december + Duration::hours(10))));
+        assert!(schedule.includes(&(december + Duration::hours(22))));
+        // nights touch at noon so there's no boundary at all
+        assert!(!schedule.did_cross_boundary(december, december + Duration::days(3)));
+    }
+
+    #[test]
+    fn polar_day_has_no_night() {
+        let schedule = solar(69.65, 18.96);
+        assert_eq!(schedule.on(date("2021-06-21"), &Utc).to_string(), "none");
+        let june = Utc.with_ymd_and_hms(2021, 6, 21, 0, 0, 0).unwrap();
+        assert!(!schedule.includes(&june));
+        assert!(!schedule.did_cross_boundary(june, june + Duration::days(3)));
+    }
+
+    #[test]
+    fn tonight_before_noon_is_last_night() {
+        let schedule = solar(52.52, 13.405);
+        let morning = Utc.with_ymd_and_hms(2021, 12, 22, 6, 0, 0).unwrap();
+        let evening = Utc.with_ymd_and_hms(2021, 12, 21, 18, 0, 0).unwrap();
+        assert_eq!(schedule.tonight(&morning).to_string(), schedule.tonight(&evening).to_string());
+    }
+
+    fn local(tz: Tz, s: &str) -> DateTime<Tz> {
+        tz.from_local_datetime(&NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap()).earliest().unwrap()
+    }
+
+    fn every_day(range: TimeRange) -> WeeklySchedule {
+        WeeklySchedule::new(range.into(), Weekdays::default(), None)
+    }
+
+    #[test]
+    fn spring_forward_night_is_an_hour_shorter() {
+        // in berlin the clocks go from 02:00 to 03:00 on 2021-03-28
+        let schedule = every_day(TimeRange::from_hmhm(22, 0, 7, 0));
+        let start = schedule.next_boundary_after(&local(Berlin, "2021-03-27 12:00")).unwrap();
+        let end = schedule.next_boundary_after(&start).unwrap();
+        assert_eq!(start, local(Berlin, "2021-03-27 22:00"));
+        assert_eq!(end, local(Berlin, "2021-03-28 07:00"));
+        assert_eq!(end - start, Duration::hours(8));
+    }
+
+    #[test]
+    fn skipped_boundary_happens_once_the_clocks_are_past_it() {
+        let schedule = every_day(TimeRange::from_hmhm(2, 30, 7, 0));
+        let start = schedule.next_boundary_after(&local(Berlin, "2021-03-27 12:00")).unwrap();
+        assert_eq!(start, local(Berlin, "2021-03-28 03:00"));
+        assert_eq!(start.with_timezone(&Utc), Utc.with_ymd_and_hms(2021, 3, 28, 1, 0, 0).unwrap());
+        assert!(!schedule.includes(&local(Berlin, "2021-03-28 01:59")));
+        assert!(schedule.includes(&local(Berlin, "2021-03-28 03:00")));
+        // a night that ends in the gap ends when the clocks change too
+        let schedule = every_day(TimeRange::from_hmhm(22, 0, 2, 30));
+        let start = schedule.next_boundary_after(&local(Berlin, "2021-03-27 12:00")).unwrap();
+        assert_eq!(schedule.next_boundary_after(&start), Some(local(Berlin, "2021-03-28 03:00")));
+    }
+
+    #[test]
+    fn fall_back_night_is_an_hour_longer() {
+        // in berlin the clocks go from 03:00 back to 02:00 on 2021-10-31
+        let schedule = every_day(TimeRange::from_hmhm(22, 0, 7, 0));
+        let start = schedule.next_boundary_after(&local(Berlin, "2021-10-30 12:00")).unwrap();
+        let end = schedule.next_boundary_after(&start).unwrap();
+        assert_eq!(start, local(Berlin, "2021-10-30 22:00"));
+        assert_eq!(end, local(Berlin, "2021-10-31 07:00"));
+        assert_eq!(end - start, Duration::hours(10));
+    }
+
+    #[test]
+    fn repeated_boundary_happens_the_first_time() {
+        let schedule = every_day(TimeRange::from_hmhm(2, 30, 7, 0));
+        let start = schedule.next_boundary_after(&local(Berlin, "2021-10-30 12:00")).unwrap();
+        assert_eq!(start.with_timezone(&Utc), Utc.with_ymd_and_hms(2021, 10, 31, 0, 30, 0).unwrap());
+        // the second time it's 02:30 it's still the same night
+        let again = Utc.with_ymd_and_hms(2021, 10, 31, 1, 30, 0).unwrap().with_timezone(&Berlin);
+        assert!(schedule.includes(&again));
+        assert_eq!(schedule.next_boundary_after(&start), Some(local(Berlin, "2021-10-31 07:00")));
+        // and a night that's over by the time the hour repeats doesn't come back
+        let schedule = every_day(TimeRange::from_hmhm(2, 15, 2, 45));
+        let start = schedule.next_boundary_after(&local(Berlin, "2021-10-30 12:00")).unwrap();
+        let end = schedule.next_boundary_after(&start).unwrap();
+        assert_eq!(end - start, Duration::minutes(30));
+        assert!(!schedule.includes(&Utc.with_ymd_and_hms(2021, 10, 31, 1, 30, 0).unwrap().with_timezone(&Berlin)));
+        assert!(!schedule.did_cross_boundary(end, local(Berlin, "2021-10-31 12:00")));
+    }
+
+    #[test]
+    fn timezone_switch_that_changes_nighttime_is_a_boundary() {
+        let schedule = every_day(TimeRange::from_hmhm(22, 0, 7, 0));
+        // 23:30 in london is 18:30 in new york
+        let since = local(London, "2021-01-04 23:30");
+        assert!(schedule.did_cross_boundary(since, since.with_timezone(&New_York)));
+        // 23:30 in london is 00:30 in berlin, nighttime either way
+        assert!(!schedule.did_cross_boundary(since, since.with_timezone(&Berlin)));
+        // the next boundary is in the new timezone
+        let until = since.with_timezone(&Berlin) + Duration::hours(7);
+        assert!(schedule.did_cross_boundary(since, until));
+        assert_eq!(schedule.next_boundary_after(&since.with_timezone(&Berlin)), Some(local(Berlin, "2021-01-05 07:00")));
+    }
+
+    #[test]
+    fn previous_boundary() {
+        let schedule = weekend();
+        assert_eq!(schedule.previous_boundary_until(&time("08T12:00")), Some(time("08T07:00")));
+        assert_eq!(schedule.previous_boundary_until(&time("08T07:00")), Some(time("08T07:00")));
+        assert_eq!(schedule.previous_boundary_until(&time("09T05:00")), Some(time("09T01:00")));
+    }
+
+    #[test]
+    fn fades_around_nighttime() {
+        let schedule = every_day(TimeRange::from_hmhm(22, 0, 7, 0));
+        let fade = Duration::minutes(30);
+        let at = |s| schedule.phase(&time(s), fade);
+        assert_eq!(at("04T21:00"), Phase::Day);
+        assert_eq!(at("04T21:30"), Phase::Day);
+        assert_eq!(at("04T21:45"), Phase::FadingIn(0.5));
+        assert_eq!(at("04T22:00"), Phase::Night);
+        assert_eq!(at("05T03:00"), Phase::Night);
+        assert_eq!(at("05T07:00"), Phase::FadingOut(1.0));
+        assert_eq!(at("05T07:06"), Phase::FadingOut(0.8));
+        assert_eq!(at("05T07:30"), Phase::Day);
+        assert_eq!(schedule.phase(&time("04T21:45"), Duration::zero()), Phase::Day);
+        assert_eq!(Phase::FadingOut(0.8).intensity(), 0.8);
+    }
+
+    #[test]
+    fn fade_between_close_nights_goes_by_the_closer_one() {
+        let schedule: WeeklySchedule = WeeklySchedule::new(nap_and_night(), Weekdays::default(), None);
+        let fade = Duration::hours(2);
+        assert_eq!(schedule.phase(&time("04T14:30"), fade), Phase::FadingOut(0.75));
+        assert_eq!(schedule.phase(&time("04T22:30"), fade), Phase::FadingIn(0.75));
+        assert_eq!(schedule.phase(&time("04T18:30"), fade), Phase::Day);
+        let fade = Duration::hours(8);
+        assert_eq!(schedule.phase(&time("04T18:00"), fade), Phase::FadingOut(0.5));
+        assert_eq!(schedule.phase(&time("04T19:00"), fade), Phase::FadingIn(0.5));
+    }
+}
@@ -0,0 +1,1235 @@
+use std::{
+    fmt,
+    sync::{Arc, mpsc::{Receiver, RecvTimeoutError, Sender}},
+    time::Duration,
+};
+use chrono::{DateTime, FixedOffset, NaiveTime, Offset, TimeZone, Duration as OldDuration};
+use log::info;
+use serde::{Serialize, Deserialize};
+
+use crate::backend::DisplayBackend;
+use crate::clock::Clock;
+use crate::restore::RestoreGuard;
+use crate::schedule::{Phase, WeeklySchedule};
+use crate::state::StateFile;
+
+/// how far off the wall clock can be after waking up before we call it a jump
+const JUMP_TOLERANCE_SECONDS: i64 = 5;
+/// how many times the display changes over a fade
+const FADE_STEPS: i32 = 60;
+/// intensities closer than this look the same
+const INTENSITY_TOLERANCE: f64 = 1e-3;
+/// longest snooze or override for a while, far enough for anyone and it can't run past the end of time
+const MAX_OVERRIDE_DAYS: i64 = 365;
+
+/// things the scheduler can be woken up for before the next boundary
+pub enum Command {
+    /// switch to a new schedule, e.g. after the config changed
+    Reload(Box<WeeklySchedule>),
+    /// check the clock at least this often from now on
+    SetMaxSleep(Duration),
+    /// fade in and out over this long from now on
+    SetFade(Duration),
+    /// force the effect on or off for as long as asked, `None` goes back to the schedule
+    SetOverride(Option<(bool, Lasting)>),
+    /// take the effect off for a while, then go back to the schedule
+    Snooze(Snooze),
+    /// send back what the scheduler is up to
+    GetState(Sender<State>),
+    /// send every change of the display from now on
+    Subscribe(Sender<Transition>),
+    /// stop running so the display can be restored
+    Stop,
+}
+
+/// how long to snooze for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Snooze {
+    For(Duration),
+    /// until the next time it's this time of day
+    Until(NaiveTime),
+}
+
+/// how long to force the effect on or off for, as it's asked for
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Lasting {
+    /// until the schedule would switch the display anyway, like a toggle in the tray
+    #[default]
+    NextBoundary,
+    Minutes(u64),
+    /// until the next time it's this time of day
+    Until(NaiveTime),
+    /// until it's turned back to the schedule
+    Forever,
+}
+
+/// the effect forced on or off whatever the schedule says
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Override {
+    pub on: bool,
+    pub until: Expiry,
+    pub reason: Reason,
+}
+
+/// when an override runs out
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Expiry {
+    /// the next nighttime boundary or the start of a fade
+    NextBoundary,
+    At(DateTime<FixedOffset>),
+    Never,
+}
+
+/// who asked for an override
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    /// on, off or a toggle from the tray or the command line
+    Manual,
+    Snooze,
+}
+
+impl Override {
+    fn intensity(&self) -> f64 {
+        if self.on { 1.0 } else { 0.0 }
+    }
+
+    /// what the display changing because of it counts as
+    fn cause(&self) -> Cause {
+        match self.reason {
+            Reason::Manual => Cause::Override,
+            Reason::Snooze => Cause::Snooze,
+        }
+    }
+}
+
+impl fmt::Display for Override {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.reason {
+            Reason::Snooze => write!(f, "snoozed")?,
+            Reason::Manual => write!(f, "forced {}", if self.on { "on" } else { "off" })?,
+        }
+        match self.until {
+            Expiry::NextBoundary => write!(f, " until the schedule switches"),
+            Expiry::At(until) => write!(f, " until {}", until.format("%a %H:%M")),
+            Expiry::Never => write!(f, " until it's put back on the schedule"),
+        }
+    }
+}
+
+/// what the scheduler is up to, times are in the timezone it's running in
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct State {
+    /// whether the schedule says it's nighttime
+    pub nighttime: bool,
+    /// how much of the effect is on the display, from 0 to 1
+    pub intensity: f64,
+    pub next_boundary: Option<DateTime<FixedOffset>>,
+    #[serde(rename = "override")]
+    pub overridden: Option<Override>,
+}
+
+/// why the display changed
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Cause {
+    /// a boundary or a step of a fade
+    Schedule,
+    /// the schedule changed
+    Reload,
+    Override,
+    /// snoozing or the snooze running out
+    Snooze,
+    /// putting the display back the way it was when we stop, subscribers are gone by then
+    Restore,
+}
+
+impl fmt::Display for Cause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Cause::Schedule => "schedule",
+            Cause::Reload => "reload",
+            Cause::Override => "override",
+            Cause::Snooze => "snooze",
+            Cause::Restore => "restore",
+        })
+    }
+}
+
+/// the display changed to `intensity` at `at`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Transition {
+    pub at: DateTime<FixedOffset>,
+    pub intensity: f64,
+    pub cause: Cause,
+}
+
+/// switches the display according to the nighttime schedule
+pub struct Scheduler<C: Clock> {
+    nighttime: WeeklySchedule,
+    backend: Arc<dyn DisplayBackend>,
+    clock: C,
+    /// how long fading in before nighttime and fading out after it takes
+    fade: OldDuration,
+    /// longest we sleep without checking the clock
+    max_sleep: Duration,
+    /// when we last checked, nothing before the first check unless the last run left it
+    previous: Option<DateTime<C::Tz>>,
+    /// whether this run has checked yet, before that the display is however it was before we started
+    started: bool,
+    /// the schedule is left alone while there's one
+    overridden: Option<Override>,
+    subscribers: Vec<Sender<Transition>>,
+    /// where to keep what has to survive a restart, nowhere unless it's set with `persist_to`
+    state: Option<StateFile>,
+    /// told about overrides that last so quitting leaves them be, nothing unless it's set with `keep_overrides`
+    guard: Option<Arc<RestoreGuard>>,
+}
+
+impl<C: Clock> Scheduler<C>
+    where <C::Tz as TimeZone>::Offset: Copy,
+{
+    pub fn new(
+        nighttime: WeeklySchedule,
+        backend: Arc<dyn DisplayBackend>,
+        clock: C,
+        fade: Duration,
+        max_sleep: Duration,
+    ) -> Self {
+        let fade = OldDuration::from_std(fade).unwrap_or_else(|_| OldDuration::zero());
+        Self { nighttime, backend, clock, fade, max_sleep, previous: None, started: false, overridden: None, subscribers: Vec::new(), state: None, guard: None }
+    }
+
+    /// have quitting leave the display the way an override put it instead of how it was before,
+    /// wherever the override came from, as long as it was meant to last until the schedule or forever
+    pub fn keep_overrides(&mut self, guard: Arc<RestoreGuard>) {
+        self.guard = Some(guard);
+    }
+
+    /// keep the state in `state` from now on and pick up where the last run left off,
+    /// an override that hasn't run out yet goes on
+    pub fn persist_to(&mut self, state: StateFile) {
+        let last_run = state.get();
+        let now = self.clock.now();
+        self.previous = last_run.last_checked.map(|checked| checked.with_timezone(&now.timezone()));
+        self.overridden = last_run.overridden.filter(|overridden| match overridden.until {
+            // the first check tells whether a boundary went by while we weren't running
+            Expiry::NextBoundary => self.previous.is_some(),
+            Expiry::At(until) => until > fixed(&now),
+            Expiry::Never => true,
+        });
+        self.state = Some(state);
+        self.save();
+    }
+
+    fn save(&self) {
+        if let Some(state) = &self.state {
+            let last_checked = self.previous.as_ref().map(fixed);
+            let overridden = self.overridden;
+            state.update(|state| {
+                state.last_checked = last_checked;
+                state.overridden = overridden;
+            });
+        }
+        // and how to leave the display, a snooze or an override for a while is over once we quit
+        if let Some(guard) = &self.guard {
+            match self.overridden {
+                Some(Override { on, until: Expiry::NextBoundary | Expiry::Never, .. }) => guard.keep(on),
+                _ => guard.reset(),
+            }
+        }
+    }
+
+    /// backends that can only switch on and off get switched at the boundaries instead
+    fn is_fading(&self) -> bool {
+        self.fade > OldDuration::zero() && self.backend.can_fade()
+    }
+
+    fn phase(&self, nighttime: &WeeklySchedule, time: &DateTime<C::Tz>) -> Phase {
+        let fade = if self.is_fading() { self.fade } else { OldDuration::zero() };
+        nighttime.phase(time, fade)
+    }
+
+    /// check the time once and switch the display if we crossed a nighttime boundary since the last check,
+    /// the first check always applies the schedule
+    pub fn tick(&mut self) -> DateTime<C::Tz> {
+        // don't reset manually set grayscale but only until next night time boundary
+        // e.g. if you turn on grayscale earlier than nighttime starts we still turn it off in the morning
+        // and if you turn off grayscale manually early in the morning we still turn it on at night
+        // this should also account for cases when the previous loop iteration was the same time period as the current one
+        // but we did cross the night time boundary in the real time, e.g. when laptop was asleep the whole day
+        let now = self.clock.now();
+        let started = std::mem::replace(&mut self.started, true);
+        if let Some(overridden) = self.overridden {
+            match overridden.until {
+                Expiry::NextBoundary if self.reached_boundary(&now) => {
+                    self.overridden = None;
+                    self.apply(&now, Cause::Schedule);
+                },
+                Expiry::At(until) if fixed(&now) >= until => {
+                    self.overridden = None;
+                    let intensity = self.target(&now);
+                    self.force(&now, intensity, overridden.cause());
+                },
+                // still overridden from before a restart
+                _ if !started => self.force(&now, overridden.intensity(), overridden.cause()),
+                _ => (),
+            }
+            self.checked(now);
+            return now;
+        }
+        let crossed = match self.previous {
+            Some(previous) if started => self.crossed(previous, now),
+            _ => true,
+        };
+        // fades take over the display until they're done
+        let fading = self.phase(&self.nighttime, &now).is_fading()
+            || matches!(self.previous, Some(previous) if self.phase(&self.nighttime, &previous).is_fading());
+        if crossed || fading {
+            self.apply(&now, Cause::Schedule);
+        }
+        self.checked(now);
+        now
+    }
+
+    fn checked(&mut self, now: DateTime<C::Tz>) {
+        self.previous = Some(now);
+        self.save();
+    }
+
+    /// the clock can also be set back, e.g. by ntp, then a boundary we already passed counts again
+    fn crossed(&self, previous: DateTime<C::Tz>, now: DateTime<C::Tz>) -> bool {
+        if now >= previous {
+            self.nighttime.did_cross_boundary(previous, now)
+        } else {
+            self.nighttime.did_cross_boundary(now, previous)
+        }
+    }
+
+    /// whether the schedule would have switched the display since the last check,
+    /// at a nighttime boundary or when a fade starts
+    fn reached_boundary(&self, now: &DateTime<C::Tz>) -> bool {
+        let previous = match self.previous {
+            Some(previous) => previous,
+            None => return false,
+        };
+        self.crossed(previous, *now)
+            || (self.phase(&self.nighttime, now).is_fading() && !self.phase(&self.nighttime, &previous).is_fading())
+    }
+
+    fn apply(&mut self, now: &DateTime<C::Tz>, cause: Cause) {
+        if !self.is_fading() {
+            let is_nighttime = self.nighttime.includes(now);
+            if is_nighttime != self.backend.is_grayscale() {
+                self.backend.set_grayscale(is_nighttime);
+                self.notify(now, cause);
+            }
+            return;
+        }
+        let current = self.backend.intensity();
+        // fading in never lightens and fading out never darkens, so if grayscale was
+        // toggled on early or off during the night it doesn't flash back at the boundary.
+        // the other way around the fade wins, grayscale taken off behind our back during
+        // a fade in comes back with the next step, turning it off through us is an override
+        // that lasts until nighttime starts
+        let intensity = match self.phase(&self.nighttime, now) {
+            Phase::FadingIn(intensity) => intensity.max(current),
+            Phase::FadingOut(intensity) => intensity.min(current),
+            phase => phase.intensity(),
+        };
+        if (intensity - current).abs() > INTENSITY_TOLERANCE {
+            self.backend.set_intensity(intensity);
+            self.notify(now, cause);
+        }
+    }
+
+    /// how much of the effect the schedule wants on at `now`
+    fn target(&self, now: &DateTime<C::Tz>) -> f64 {
+        if self.is_fading() {
+            self.phase(&self.nighttime, now).intensity()
+        } else if self.nighttime.includes(now) {
+            1.0
+        } else {
+            0.0
+        }
+    }
+
+    /// put exactly `intensity` on the display, whatever is on it now
+    fn force(&mut self, now: &DateTime<C::Tz>, intensity: f64, cause: Cause) {
+        if (intensity - self.backend.intensity()).abs() > INTENSITY_TOLERANCE {
+            self.backend.set_intensity(intensity);
+            self.notify(now, cause);
+        }
+    }
+
+    /// tell the subscribers what's on the display now, the ones that went away are dropped
+    fn notify(&mut self, now: &DateTime<C::Tz>, cause: Cause) {
+        let transition = Transition { at: fixed(now), intensity: self.backend.intensity(), cause };
+        let overridden = self.overridden.map_or_else(|| "none".to_owned(), |overridden| format!("{:?}", overridden.to_string()));
+        info!(
+            target: "transition",
+            "intensity={:.2} cause={} nighttime={} override={}",
+            transition.intensity, cause, self.nighttime.includes(now), overridden,
+        );
+        self.subscribers.retain(|subscriber| subscriber.send(transition.clone()).is_ok());
+    }
+
+    /// when something could happen next after `now`
+    fn next_change(&self, now: &DateTime<C::Tz>) -> Option<DateTime<C::Tz>> {
+        match self.overridden.map(|overridden| overridden.until) {
+            Some(Expiry::At(until)) => return Some(until.with_timezone(&now.timezone())),
+            Some(Expiry::Never) => return None,
+            Some(Expiry::NextBoundary) | None => (),
+        }
+        let boundary = self.nighttime.next_boundary_after(now);
+        let step = *now + (self.fade / FADE_STEPS).max(OldDuration::seconds(1));
+        match self.phase(&self.nighttime, now) {
+            Phase::FadingIn(_) | Phase::FadingOut(_) => Some(boundary.map_or(step, |boundary| boundary.min(step))),
+            // the fade starts ahead of nighttime, unless it's starting right now
+            Phase::Day if self.is_fading() => boundary.map(|start| {
+                let fade_in = start - self.fade;
+                if fade_in > *now { fade_in } else { step }
+            }),
+            Phase::Day | Phase::Night => boundary,
+        }
+    }
+
+    /// a new schedule only changes the display if it disagrees with the old one about right now,
+    /// so a manual toggle survives editing an unrelated part of the config
+    fn reload(&mut self, nighttime: WeeklySchedule) {
+        let now = self.clock.now();
+        let changed = self.phase(&nighttime, &now) != self.phase(&self.nighttime, &now);
+        self.nighttime = nighttime;
+        if changed && self.overridden.is_none() {
+            self.apply(&now, Cause::Reload);
+        }
+    }
+
+    /// like a new schedule, a new fade only changes the display if it disagrees with the old one about right now,
+    /// then it's put where the new fade is even if that's lighter, a fade that's gone shouldn't leave it halfway
+    fn set_fade(&mut self, fade: Duration) {
+        let now = self.clock.now();
+        let before = self.phase(&self.nighttime, &now);
+        self.fade = OldDuration::from_std(fade).unwrap_or_else(|_| OldDuration::zero());
+        if self.phase(&self.nighttime, &now) != before && self.overridden.is_none() {
+            let intensity = self.target(&now);
+            self.force(&now, intensity, Cause::Reload);
+        }
+    }
+
+    /// force the effect on or off for as long as `lasting` says, this replaces a snooze
+    /// or an earlier override, `None` puts the display back on the schedule right away
+    fn set_override(&mut self, on: Option<(bool, Lasting)>) {
+        let now = self.clock.now();
+        let overridden = on.map(|(on, lasting)| {
+            let until = match lasting {
+                Lasting::NextBoundary => Expiry::NextBoundary,
+                Lasting::Minutes(minutes) => Expiry::At(later(&now, OldDuration::minutes(minutes.min(MAX_OVERRIDE_DAYS as u64 * 24 * 60) as i64))),
+                Lasting::Until(time) => Expiry::At(fixed(&next_time_of_day(&now, time))),
+                Lasting::Forever => Expiry::Never,
+            };
+            Override { on, until, reason: Reason::Manual }
+        });
+        self.overridden = overridden;
+        self.save();
+        match overridden {
+            Some(overridden) => self.force(&now, overridden.intensity(), Cause::Override),
+            None => {
+                let intensity = self.target(&now);
+                self.force(&now, intensity, Cause::Override);
+            },
+        }
+    }
+
+    fn snooze(&mut self, snooze: Snooze) {
+        let now = self.clock.now();
+        let until = match snooze {
+            Snooze::For(duration) => later(&now, OldDuration::from_std(duration).unwrap_or(OldDuration::MAX)),
+            Snooze::Until(time) => fixed(&next_time_of_day(&now, time)),
+        };
+        self.overridden = Some(Override { on: false, until: Expiry::At(until), reason: Reason::Snooze });
+        self.save();
+        self.force(&now, 0.0, Cause::Snooze);
+    }
+
+    fn state(&self) -> State {
+        let now = self.clock.now();
+        State {
+            nighttime: self.nighttime.includes(&now),
+            intensity: self.backend.intensity(),
+            next_boundary: self.nighttime.next_boundary_after(&now).as_ref().map(fixed),
+            overridden: self.overridden,
+        }
+    }
+
+    /// check the time, then wait until the next boundary or the next step of a fade
+    /// but at most `max_sleep` and handle whatever woke us up, return `false` once we should stop
+    pub fn step(&mut self, commands: &Receiver<Command>) -> bool {
+        let now = self.tick();
+        let max_sleep = OldDuration::from_std(self.max_sleep).unwrap_or(OldDuration::MAX);
+        // waking up every now and then even without a boundary coming up is how we
+        // notice the wall clock jumping, the timeout doesn't know about any of that
+        let sleep = match self.next_change(&now) {
+            Some(change) => (change - now).min(max_sleep),
+            None => max_sleep,
+        };
+        match self.clock.wait(commands, sleep.to_std().unwrap_or_default()) {
+            Ok(Command::Reload(nighttime)) => self.reload(*nighttime),
+            Ok(Command::SetMaxSleep(max_sleep)) => self.max_sleep = max_sleep,
+            Ok(Command::SetFade(fade)) => self.set_fade(fade),
+            Ok(Command::SetOverride(on)) => self.set_override(on),
+            Ok(Command::Snooze(snooze)) => self.snooze(snooze),
+            Ok(Command::GetState(reply)) => {
+                reply.send(self.state()).ok();
+            },
+            Ok(Command::Subscribe(subscriber)) => self.subscribers.push(subscriber),
+            Ok(Command::Stop) | Err(RecvTimeoutError::Disconnected) => return false,
+            Err(RecvTimeoutError::Timeout) => {
+                // the next tick takes care of the jump, this is just so it doesn't go unnoticed
+                if let Some(expected) = now.checked_add_signed(sleep) {
+                    let drift = self.clock.now() - expected;
+                    if drift.num_seconds().abs() > JUMP_TOLERANCE_SECONDS {
+                        info!("clock jumped by {} seconds", drift.num_seconds());
+                    }
+                }
+            },
+        }
+        true
+    }
+
+    /// keep switching the display at every boundary until told to stop
+    pub fn run(mut self, commands: Receiver<Command>) {
+        while self.step(&commands) {}
+    }
+}
+
+/// the first time after `now` that the clock shows `time`, on a day the clocks
+/// skip over it that's a day later
+fn next_time_of_day<Tz: TimeZone>(now: &DateTime<Tz>, time: NaiveTime) -> DateTime<Tz> {
+    (0..=2)
+        .filter_map(|days| {
+            let date = now.naive_local().date() + OldDuration::days(days);
+            now.timezone().from_local_datetime(&date.and_time(time)).earliest()
+        })
+        .find(|candidate| candidate > now)
+        .unwrap_or_else(|| now.clone() + OldDuration::days(1))
+}
+
+/// `duration` after `now`, but no more than `MAX_OVERRIDE_DAYS`
+fn later<Tz: TimeZone>(now: &DateTime<Tz>, duration: OldDuration) -> DateTime<FixedOffset> {
+    fixed(&(now.clone() + duration.min(OldDuration::days(MAX_OVERRIDE_DAYS))))
+}
+
+/// the same instant with the offset it has, which is all that's needed to show or send it
+fn fixed<Tz: TimeZone>(time: &DateTime<Tz>) -> DateTime<FixedOffset> {
+    time.with_timezone(&time.offset().fix())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc::{self, Sender};
+    use chrono::{NaiveDateTime, Utc};
+    use chrono_tz::{Tz, America::New_York, Europe::{Berlin, London}};
+
+    use super::*;
+    use crate::backend::RecordingBackend;
+    use crate::clock::FakeClock;
+    use crate::schedule::Weekdays;
+    use crate::state::RuntimeState;
+    use crate::timerange::TimeRange;
+
+    fn setup_with(
+        start: &str,
+        grayscale: bool,
+        nighttime: WeeklySchedule,
+    ) -> (Scheduler<FakeClock<Utc>>, Arc<RecordingBackend>, FakeClock<Utc>) {
+        let clock = FakeClock::new(DateTime::parse_from_rfc3339(start).unwrap().with_timezone(&Utc));
+        let backend = Arc::new(RecordingBackend::new(grayscale));
+        let scheduler = Scheduler::new(nighttime, backend.clone(), clock.clone(), Duration::from_secs(0), DAY);
+        (scheduler, backend, clock)
+    }
+
+    fn setup(start: &str, grayscale: bool) -> (Scheduler<FakeClock<Utc>>, Arc<RecordingBackend>, FakeClock<Utc>) {
+        setup_with(start, grayscale, WeeklySchedule::new(TimeRange::from_hmhm(22, 0, 7, 0).into(), Weekdays::default(), None))
+    }
+
+    fn setup_in(tz: Tz, start: &str, nighttime: WeeklySchedule) -> (Scheduler<FakeClock<Tz>>, Arc<RecordingBackend>, FakeClock<Tz>) {
+        let start = tz.from_local_datetime(&NaiveDateTime::parse_from_str(start, "%Y-%m-%d %H:%M:%S").unwrap()).unwrap();
+        let clock = FakeClock::new(start);
+        let backend = Arc::new(RecordingBackend::new(false));
+        let scheduler = Scheduler::new(nighttime, backend.clone(), clock.clone(), Duration::from_secs(0), DAY);
+        (scheduler, backend, clock)
+    }
+
+    /// tick once a minute for `minutes` and return when the display was switched
+    fn simulate<Tz: TimeZone>(
+        scheduler: &mut Scheduler<FakeClock<Tz>>,
+        backend: &RecordingBackend,
+        clock: &FakeClock<Tz>,
+        minutes: i64,
+    ) -> Vec<(String, bool)>
+        where Tz::Offset: Copy + std::fmt::Display,
+    {
+        let mut switches = Vec::new();
+        for _ in 0..minutes {
+            scheduler.tick();
+            for on in backend.take_switches() {
+                switches.push((clock.now().format("%a %H:%M").to_string(), on));
+            }
+            clock.advance(OldDuration::minutes(1));
+        }
+        switches
+    }
+
+    fn at(time: &str, on: bool) -> (String, bool) {
+        (time.to_owned(), on)
+    }
+
+    #[test]
+    fn switches_at_boundaries_for_a_week() {
+        // 2021-01-04 is a monday
+        let (mut scheduler, backend, clock) = setup("2021-01-04T12:00:30-00:00", false);
+        let switches = simulate(&mut scheduler, &backend, &clock, 7 * 24 * 60);
+        let days = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun", "Mon"];
+        let mut expected = Vec::new();
+        for day in days.windows(2) {
+            expected.push(at(&format!("{} 22:00", day[0]), true));
+            expected.push(at(&format!("{} 07:00", day[1]), false));
+        }
+        assert_eq!(switches, expected);
+    }
+
+    #[test]
+    fn switches_on_weekday_schedule() {
+        let nighttime = WeeklySchedule::new(TimeRange::from_hmhm(23, 0, 7, 0).into(), Weekdays {
+            fri: Some(TimeRange::from_hmhm(1, 0, 10, 0).into()),
+            sat: Some(TimeRange::from_hmhm(1, 0, 10, 0).into()),
+            ..Weekdays::default()
+        }, None);
+        // 2021-01-07 is a thursday
+        let (mut scheduler, backend, clock) = setup_with("2021-01-07T12:00:30-00:00", false, nighttime);
+        let switches = simulate(&mut scheduler, &backend, &clock, 4 * 24 * 60);
+        assert_eq!(switches, vec![
+            at("Thu 23:00", true), at("Fri 07:00", false),
+            at("Sat 01:00", true), at("Sat 10:00", false),
+            at("Sun 01:00", true), at("Sun 10:00", false),
+            at("Sun 23:00", true), at("Mon 07:00", false),
+        ]);
+    }
+
+    #[test]
+    fn switches_for_nap_and_night() {
+        let nighttime = vec![
+            TimeRange::from_hmhm(13, 0, 14, 0),
+            TimeRange::from_hmhm(14, 0, 15, 0),
+            TimeRange::from_hmhm(23, 0, 7, 0),
+            TimeRange::from_hmhm(6, 0, 8, 0),
+        ];
+        let nighttime = WeeklySchedule::new(nighttime.into(), Weekdays::default(), None);
+        let (mut scheduler, backend, clock) = setup_with("2021-01-04T12:00:30-00:00", false, nighttime);
+        let switches = simulate(&mut scheduler, &backend, &clock, 24 * 60);
+        assert_eq!(switches, vec![
+            at("Mon 13:00", true), at("Mon 15:00", false),
+            at("Mon 23:00", true), at("Tue 08:00", false),
+        ]);
+    }
+
+    #[test]
+    fn first_tick_applies_schedule() {
+        let (mut scheduler, backend, _) = setup("2021-01-04T23:00:30-00:00", false);
+        scheduler.tick();
+        assert_eq!(backend.take_switches(), vec![true]);
+    }
+
+    #[test]
+    fn first_tick_keeps_matching_state() {
+        let (mut scheduler, backend, _) = setup("2021-01-04T12:00:30-00:00", false);
+        scheduler.tick();
+        assert_eq!(backend.take_switches(), Vec::<bool>::new());
+    }
+
+    #[test]
+    fn laptop_sleep_across_boundary() {
+        let (mut scheduler, backend, clock) = setup("2021-01-04T21:00:30-00:00", false);
+        scheduler.tick();
+        clock.advance(OldDuration::hours(3));
+        let switches = simulate(&mut scheduler, &backend, &clock, 1);
+        assert_eq!(switches, vec![at("Tue 00:00", true)]);
+    }
+
+    #[test]
+    fn laptop_sleep_across_whole_night() {
+        let (mut scheduler, backend, clock) = setup("2021-01-04T21:00:30-00:00", false);
+        scheduler.tick();
+        clock.advance(OldDuration::hours(12));
+        let switches = simulate(&mut scheduler, &backend, &clock, 1);
+        assert_eq!(switches, vec![]);
+    }
+
+    #[test]
+    fn laptop_sleep_for_a_whole_day_resets_manual_toggle() {
+        let (mut scheduler, backend, clock) = setup("2021-01-04T23:00:30-00:00", false);
+        scheduler.tick();
+        backend.set_grayscale(false);
+        backend.take_switches();
+        clock.advance(OldDuration::days(1));
+        let switches = simulate(&mut scheduler, &backend, &clock, 1);
+        assert_eq!(switches, vec![at("Tue 23:00", true)]);
+    }
+
+    #[test]
+    fn manual_toggle_on_is_kept_until_morning() {
+        let (mut scheduler, backend, clock) = setup("2021-01-04T18:00:30-00:00", false);
+        scheduler.tick();
+        backend.set_grayscale(true);
+        backend.take_switches();
+        let switches = simulate(&mut scheduler, &backend, &clock, 29 * 60);
+        assert_eq!(switches, vec![at("Tue 07:00", false), at("Tue 22:00", true)]);
+    }
+
+    #[test]
+    fn manual_toggle_off_is_kept_until_next_night() {
+        let (mut scheduler, backend, clock) = setup("2021-01-04T23:00:30-00:00", false);
+        scheduler.tick();
+        backend.set_grayscale(false);
+        backend.take_switches();
+        let switches = simulate(&mut scheduler, &backend, &clock, 24 * 60);
+        assert_eq!(switches, vec![at("Tue 22:00", true)]);
+    }
+
+    /// step until the clock passes `until` and return when the display was switched
+    fn run_until(
+        scheduler: &mut Scheduler<FakeClock<Utc>>,
+        backend: &RecordingBackend,
+        clock: &FakeClock<Utc>,
+        commands: &Receiver<Command>,
+        until: &str,
+    ) -> Vec<(String, bool)> {
+        let until = DateTime::parse_from_rfc3339(until).unwrap();
+        let mut switches = Vec::new();
+        while clock.now() < until {
+            scheduler.step(commands);
+            for on in backend.take_switches() {
+                switches.push((scheduler.previous.unwrap().format("%a %H:%M:%S").to_string(), on));
+            }
+        }
+        switches
+    }
+
+    fn channel() -> (Sender<Command>, Receiver<Command>) {
+        mpsc::channel()
+    }
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    #[test]
+    fn sleeps_until_the_boundary() {
+        let (_commands, receiver) = channel();
+        let (mut scheduler, backend, clock) = setup("2021-01-04T12:00:30-00:00", false);
+        scheduler.step(&receiver);
+        assert_eq!(clock.now().format("%a %H:%M:%S").to_string(), "Mon 22:00:00");
+        let switches = run_until(&mut scheduler, &backend, &clock, &receiver, "2021-01-06T12:00:00-00:00");
+        assert_eq!(switches, vec![
+            at("Mon 22:00:00", true), at("Tue 07:00:00", false),
+            at("Tue 22:00:00", true), at("Wed 07:00:00", false),
+        ]);
+    }
+
+    #[test]
+    fn sleeps_at_most_max_sleep() {
+        let (_commands, receiver) = channel();
+        let (mut scheduler, _, clock) = setup("2021-01-04T12:00:30-00:00", false);
+        scheduler.max_sleep = Duration::from_secs(60);
+        scheduler.step(&receiver);
+        assert_eq!(clock.now().format("%H:%M:%S").to_string(), "12:01:30");
+    }
+
+    #[test]
+    fn max_sleep_changes_from_the_next_sleep() {
+        let (commands, receiver) = channel();
+        let (mut scheduler, _, clock) = setup("2021-01-04T12:00:30-00:00", false);
+        commands.send(Command::SetMaxSleep(Duration::from_secs(60))).unwrap();
+        scheduler.step(&receiver);
+        assert_eq!(clock.now().format("%H:%M:%S").to_string(), "12:00:30");
+        scheduler.step(&receiver);
+        assert_eq!(clock.now().format("%H:%M:%S").to_string(), "12:01:30");
+    }
+
+    #[test]
+    fn sleeps_without_boundaries() {
+        let (_commands, receiver) = channel();
+        let nighttime = WeeklySchedule::new(vec![TimeRange::from_hmhm(12, 0, 12, 0)].into(), Weekdays::default(), None);
+        let (mut scheduler, backend, clock) = setup_with("2021-01-04T12:00:30-00:00", false, nighttime);
+        scheduler.step(&receiver);
+        assert_eq!(backend.take_switches(), vec![true]);
+        assert_eq!(clock.now().format("%a %H:%M:%S").to_string(), "Tue 12:00:30");
+    }
+
+    #[test]
+    fn reload_wakes_up_and_applies_new_schedule() {
+        let (commands, receiver) = channel();
+        let (mut scheduler, backend, clock) = setup("2021-01-04T20:00:30-00:00", false);
+        let nighttime = WeeklySchedule::new(TimeRange::from_hmhm(20, 0, 7, 0).into(), Weekdays::default(), None);
+        commands.send(Command::Reload(Box::new(nighttime))).unwrap();
+        assert!(scheduler.step(&receiver));
+        assert_eq!(clock.now().format("%H:%M:%S").to_string(), "20:00:30");
+        assert_eq!(backend.take_switches(), vec![true]);
+    }
+
+    #[test]
+    fn reload_keeps_manual_toggle_if_nothing_changes_now() {
+        let (commands, receiver) = channel();
+        let (mut scheduler, backend, _) = setup("2021-01-04T20:00:30-00:00", false);
+        scheduler.tick();
+        backend.set_grayscale(true);
+        backend.take_switches();
+        let nighttime = WeeklySchedule::new(TimeRange::from_hmhm(23, 0, 7, 0).into(), Weekdays::default(), None);
+        commands.send(Command::Reload(Box::new(nighttime))).unwrap();
+        scheduler.step(&receiver);
+        assert_eq!(backend.take_switches(), Vec::<bool>::new());
+    }
+
+    #[test]
+    fn stops_when_told_or_when_nobody_is_left() {
+        let (commands, receiver) = channel();
+        let (mut scheduler, _, _) = setup("2021-01-04T20:00:30-00:00", false);
+        commands.send(Command::Stop).unwrap();
+        assert!(!scheduler.step(&receiver));
+        drop(commands);
+        assert!(!scheduler.step(&receiver));
+    }
+
+    #[test]
+    fn clock_set_back_across_boundary() {
+        let (_commands, receiver) = channel();
+        let (mut scheduler, backend, clock) = setup("2021-01-04T22:30:00-00:00", false);
+        scheduler.tick();
+        assert_eq!(backend.take_switches(), vec![true]);
+        clock.advance(OldDuration::hours(-1));
+        scheduler.step(&receiver);
+        assert_eq!(backend.take_switches(), vec![false]);
+        // and forward again once it's nighttime
+        assert_eq!(clock.now().format("%H:%M:%S").to_string(), "22:00:00");
+        scheduler.step(&receiver);
+        assert_eq!(backend.take_switches(), vec![true]);
+    }
+
+    fn night() -> WeeklySchedule {
+        WeeklySchedule::new(TimeRange::from_hmhm(22, 0, 7, 0).into(), Weekdays::default(), None)
+    }
+
+    #[test]
+    fn spring_forward() {
+        let (mut scheduler, backend, clock) = setup_in(Berlin, "2021-03-27 12:00:30", night());
+        let switches = simulate(&mut scheduler, &backend, &clock, 23 * 60);
+        assert_eq!(switches, vec![at("Sat 22:00", true), at("Sun 07:00", false)]);
+    }
+
+    #[test]
+    fn fall_back() {
+        let (mut scheduler, backend, clock) = setup_in(Berlin, "2021-10-30 12:00:30", night());
+        let switches = simulate(&mut scheduler, &backend, &clock, 25 * 60);
+        assert_eq!(switches, vec![at("Sat 22:00", true), at("Sun 07:00", false)]);
+    }
+
+    #[test]
+    fn fall_back_doesnt_repeat_a_night_in_the_repeated_hour() {
+        let nighttime = WeeklySchedule::new(TimeRange::from_hmhm(2, 15, 2, 45).into(), Weekdays::default(), None);
+        let (mut scheduler, backend, clock) = setup_in(Berlin, "2021-10-30 12:00:30", nighttime);
+        let switches = simulate(&mut scheduler, &backend, &clock, 25 * 60);
+        assert_eq!(switches, vec![at("Sun 02:15", true), at("Sun 02:45", false)]);
+    }
+
+    #[test]
+    fn timezone_switch_in_the_middle_of_the_night() {
+        let (mut scheduler, backend, clock) = setup_in(London, "2021-01-04 23:00:30", night());
+        assert_eq!(simulate(&mut scheduler, &backend, &clock, 1), vec![at("Mon 23:00", true)]);
+        // flying west it's suddenly the evening before
+        clock.set_timezone(&New_York);
+        let switches = simulate(&mut scheduler, &backend, &clock, 14 * 60);
+        assert_eq!(switches, vec![at("Mon 18:01", false), at("Mon 22:00", true), at("Tue 07:00", false)]);
+    }
+
+    #[test]
+    fn timezone_switch_that_keeps_nighttime_keeps_manual_toggle() {
+        let (mut scheduler, backend, clock) = setup_in(London, "2021-01-04 23:00:30", night());
+        simulate(&mut scheduler, &backend, &clock, 1);
+        backend.set_grayscale(false);
+        backend.take_switches();
+        clock.set_timezone(&Berlin);
+        let switches = simulate(&mut scheduler, &backend, &clock, 60);
+        assert_eq!(switches, vec![]);
+    }
+
+    fn setup_fading(start: &str, backend: RecordingBackend) -> (Scheduler<FakeClock<Utc>>, Arc<RecordingBackend>, FakeClock<Utc>) {
+        let clock = FakeClock::new(DateTime::parse_from_rfc3339(start).unwrap().with_timezone(&Utc));
+        let backend = Arc::new(backend);
+        let scheduler = Scheduler::new(night(), backend.clone(), clock.clone(), Duration::from_secs(30 * 60), DAY);
+        (scheduler, backend, clock)
+    }
+
+    /// step until the clock passes `until` and return every intensity that was set
+    fn fade_until(
+        scheduler: &mut Scheduler<FakeClock<Utc>>,
+        backend: &RecordingBackend,
+        clock: &FakeClock<Utc>,
+        until: &str,
+    ) -> Vec<(String, f64)> {
+        let (_commands, receiver) = channel();
+        let until = DateTime::parse_from_rfc3339(until).unwrap();
+        let mut intensities = Vec::new();
+        while clock.now() < until {
+            scheduler.step(&receiver);
+            for intensity in backend.take_intensities() {
+                intensities.push((scheduler.previous.unwrap().format("%H:%M:%S").to_string(), intensity));
+            }
+        }
+        intensities
+    }
+
+    #[test]
+    fn fades_in_before_and_out_after_nighttime() {
+        let (mut scheduler, backend, clock) = setup_fading("2021-01-04T12:00:30-00:00", RecordingBackend::fading(0.0));
+        let intensities = fade_until(&mut scheduler, &backend, &clock, "2021-01-05T12:00:00-00:00");
+        assert_eq!(intensities.len(), 2 * FADE_STEPS as usize);
+        let (fade_in, fade_out) = intensities.split_at(FADE_STEPS as usize);
+        assert_eq!(fade_in[0].0, "21:30:30");
+        assert!(fade_in.contains(&("21:45:00".to_owned(), 0.5)));
+        assert_eq!(fade_in.last().unwrap(), &("22:00:00".to_owned(), 1.0));
+        assert!(fade_in.windows(2).all(|pair| pair[0].1 < pair[1].1));
+        assert_eq!(fade_out[0].0, "07:00:30");
+        assert_eq!(fade_out.last().unwrap(), &("07:30:00".to_owned(), 0.0));
+        assert!(fade_out.windows(2).all(|pair| pair[0].1 > pair[1].1));
+        assert_eq!(backend.take_switches(), Vec::<bool>::new());
+    }
+
+    #[test]
+    fn new_fade_applies_right_away() {
+        let (commands, receiver) = channel();
+        let (mut scheduler, backend, _) = setup_fading("2021-01-04T21:45:00-00:00", RecordingBackend::fading(0.0));
+        scheduler.tick();
+        assert_eq!(backend.take_intensities(), vec![0.5]);
+        commands.send(Command::SetFade(Duration::from_secs(0))).unwrap();
+        scheduler.step(&receiver);
+        assert_eq!(backend.intensity(), 0.0);
+        commands.send(Command::SetFade(Duration::from_secs(60 * 60))).unwrap();
+        scheduler.step(&receiver);
+        assert_eq!(backend.intensity(), 0.75);
+    }
+
+    #[test]
+    fn sleeps_until_the_fade_starts() {
+        let (_commands, receiver) = channel();
+        let (mut scheduler, _, clock) = setup_fading("2021-01-04T12:00:30-00:00", RecordingBackend::fading(0.0));
+        scheduler.step(&receiver);
+        assert_eq!(clock.now().format("%H:%M:%S").to_string(), "21:30:00");
+        scheduler.step(&receiver);
+        assert_eq!(clock.now().format("%H:%M:%S").to_string(), "21:30:30");
+    }
+
+    #[test]
+    fn backend_without_fading_switches_at_boundaries() {
+        let (mut scheduler, backend, clock) = setup_fading("2021-01-04T12:00:30-00:00", RecordingBackend::new(false));
+        let switches = simulate(&mut scheduler, &backend, &clock, 24 * 60);
+        assert_eq!(switches, vec![at("Mon 22:00", true), at("Tue 07:00", false)]);
+    }
+
+    #[test]
+    fn fade_out_doesnt_bring_back_grayscale_turned_off_at_night() {
+        let (mut scheduler, backend, clock) = setup_fading("2021-01-04T23:00:30-00:00", RecordingBackend::fading(0.0));
+        scheduler.tick();
+        backend.set_grayscale(false);
+        backend.take_intensities();
+        let intensities = fade_until(&mut scheduler, &backend, &clock, "2021-01-05T12:00:00-00:00");
+        assert_eq!(intensities, vec![]);
+    }
+
+    #[test]
+    fn fade_in_doesnt_lighten_grayscale_turned_on_early() {
+        let (mut scheduler, backend, clock) = setup_fading("2021-01-04T20:00:30-00:00", RecordingBackend::fading(0.0));
+        scheduler.tick();
+        backend.set_grayscale(true);
+        let intensities = fade_until(&mut scheduler, &backend, &clock, "2021-01-04T23:00:00-00:00");
+        assert_eq!(intensities, vec![]);
+    }
+
+    #[test]
+    fn fade_in_takes_back_grayscale_turned_off_behind_its_back() {
+        let (mut scheduler, backend, clock) = setup_fading("2021-01-04T21:45:00-00:00", RecordingBackend::fading(0.0));
+        scheduler.tick();
+        backend.set_grayscale(false);
+        backend.take_intensities();
+        let intensities = fade_until(&mut scheduler, &backend, &clock, "2021-01-04T21:47:00-00:00");
+        assert_eq!(intensities.first(), Some(&("21:45:00".to_owned(), 0.5)));
+        assert!(intensities.windows(2).all(|pair| pair[0].1 < pair[1].1));
+    }
+
+    #[test]
+    fn turning_off_during_fade_in_lasts_until_nighttime() {
+        let (commands, receiver) = channel();
+        let (mut scheduler, backend, clock) = setup_fading("2021-01-04T21:45:00-00:00", RecordingBackend::fading(0.0));
+        scheduler.tick();
+        backend.take_intensities();
+        commands.send(force(false, Lasting::NextBoundary)).unwrap();
+        scheduler.step(&receiver);
+        assert_eq!(backend.take_intensities(), vec![0.0]);
+        let intensities = fade_until(&mut scheduler, &backend, &clock, "2021-01-04T22:01:00-00:00");
+        assert_eq!(intensities, vec![("22:00:00".to_owned(), 1.0)]);
+    }
+
+    #[test]
+    fn snooze_takes_the_effect_off_and_comes_back() {
+        let (commands, receiver) = channel();
+        let (mut scheduler, backend, clock) = setup("2021-01-04T22:30:00-00:00", false);
+        commands.send(Command::Snooze(Snooze::For(Duration::from_secs(60 * 60)))).unwrap();
+        let switches = run_until(&mut scheduler, &backend, &clock, &receiver, "2021-01-05T00:00:00-00:00");
+        assert_eq!(switches, vec![at("Mon 22:30:00", true), at("Mon 22:30:00", false), at("Mon 23:30:00", true)]);
+    }
+
+    #[test]
+    fn snooze_until_a_time() {
+        let (commands, receiver) = channel();
+        let (mut scheduler, backend, clock) = setup("2021-01-04T23:00:00-00:00", true);
+        commands.send(Command::Snooze(Snooze::Until(NaiveTime::from_hms_opt(1, 30, 0).unwrap()))).unwrap();
+        scheduler.step(&receiver);
+        let until = DateTime::parse_from_rfc3339("2021-01-05T01:30:00+00:00").unwrap();
+        assert_eq!(scheduler.state().overridden, Some(Override { on: false, until: Expiry::At(until), reason: Reason::Snooze }));
+        let switches = run_until(&mut scheduler, &backend, &clock, &receiver, "2021-01-05T12:00:00-00:00");
+        assert_eq!(switches, vec![at("Mon 23:00:00", false), at("Tue 01:30:00", true), at("Tue 07:00:00", false)]);
+    }
+
+    #[test]
+    fn override_ends_snooze() {
+        let (commands, receiver) = channel();
+        let (mut scheduler, backend, clock) = setup("2021-01-04T23:00:00-00:00", true);
+        commands.send(Command::Snooze(Snooze::For(DAY))).unwrap();
+        scheduler.step(&receiver);
+        commands.send(Command::SetOverride(None)).unwrap();
+        scheduler.step(&receiver);
+        assert_eq!(backend.take_switches(), vec![false, true]);
+        assert_eq!(scheduler.state().overridden, None);
+        assert_eq!(clock.now().format("%a %H:%M").to_string(), "Mon 23:00");
+    }
+
+    /// run a scheduler that keeps overrides with `guard` through `commands`, the display starts out off
+    fn keeping(commands: Vec<Command>) -> (Scheduler<FakeClock<Utc>>, Arc<RestoreGuard>, Arc<RecordingBackend>, tempfile::TempDir) {
+        let directory = tempfile::tempdir().unwrap();
+        let (sender, receiver) = channel();
+        let (mut scheduler, backend, _) = setup("2021-01-04T21:00:00-00:00", false);
+        let guard = Arc::new(RestoreGuard::take(backend.clone(), StateFile::open(directory.path().join("state.yaml"))));
+        scheduler.keep_overrides(Arc::clone(&guard));
+        for command in commands {
+            sender.send(command).unwrap();
+            scheduler.step(&receiver);
+        }
+        (scheduler, guard, backend, directory)
+    }
+
+    /// quit and say whether the display was left on
+    fn quit(scheduler: Scheduler<FakeClock<Utc>>, guard: Arc<RestoreGuard>, backend: Arc<RecordingBackend>) -> bool {
+        drop(scheduler);
+        guard.restore();
+        backend.is_grayscale()
+    }
+
+    #[test]
+    fn quitting_keeps_an_override_from_anywhere() {
+        // like `goodnight on` over the socket, not just the tray
+        let (scheduler, guard, backend, _directory) = keeping(vec![force(true, Lasting::NextBoundary)]);
+        assert!(quit(scheduler, guard, backend));
+        let (scheduler, guard, backend, _directory) = keeping(vec![force(true, Lasting::Forever)]);
+        assert!(quit(scheduler, guard, backend));
+    }
+
+    #[test]
+    fn quitting_puts_back_what_was_only_for_a_while() {
+        let (scheduler, guard, backend, _directory) = keeping(vec![force(true, Lasting::Minutes(30))]);
+        assert!(!quit(scheduler, guard, backend));
+        let (scheduler, guard, backend, _directory) = keeping(vec![force(true, Lasting::Forever), force(true, Lasting::Minutes(30))]);
+        assert!(!quit(scheduler, guard, backend));
+        let (scheduler, guard, backend, _directory) = keeping(vec![force(true, Lasting::Forever), Command::Snooze(Snooze::For(DAY))]);
+        assert!(!quit(scheduler, guard, backend));
+    }
+
+    #[test]
+    fn quitting_after_going_back_to_the_schedule() {
+        let (scheduler, guard, backend, _directory) = keeping(vec![force(true, Lasting::Forever), Command::SetOverride(None)]);
+        assert!(!quit(scheduler, guard, backend));
+
+        // or after the override ran out by itself
+        let (mut scheduler, guard, backend, _directory) = keeping(vec![force(true, Lasting::NextBoundary)]);
+        scheduler.clock.advance(OldDuration::hours(2));
+        scheduler.tick();
+        assert_eq!(scheduler.state().overridden, None);
+        assert!(!quit(scheduler, guard, backend));
+    }
+
+    #[test]
+    fn snooze_survives_a_restart() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("state.yaml");
+        let (commands, receiver) = channel();
+        let (mut scheduler, _, clock) = setup("2021-01-04T23:00:00-00:00", true);
+        scheduler.persist_to(StateFile::open(path.clone()));
+        commands.send(Command::Snooze(Snooze::For(Duration::from_secs(60 * 60)))).unwrap();
+        scheduler.step(&receiver);
+        drop(scheduler);
+
+        // the display is back the way it was before the app started
+        let backend = Arc::new(RecordingBackend::new(false));
+        let mut scheduler = Scheduler::new(night(), backend.clone(), clock.clone(), Duration::from_secs(0), DAY);
+        scheduler.persist_to(StateFile::open(path.clone()));
+        clock.advance(OldDuration::minutes(10));
+        let switches = run_until(&mut scheduler, &backend, &clock, &receiver, "2021-01-05T01:00:00-00:00");
+        assert_eq!(switches, vec![at("Tue 00:00:00", true)]);
+        assert_eq!(RuntimeState::load(&path).overridden, None);
+
+        // but not one that ran out while the app wasn't running
+        let until = DateTime::parse_from_rfc3339("2021-01-05T00:30:00+00:00").unwrap();
+        let snooze = Override { on: false, until: Expiry::At(until), reason: Reason::Snooze };
+        RuntimeState { overridden: Some(snooze), ..RuntimeState::default() }.store(&path);
+        let (mut scheduler, backend, _) = setup("2021-01-05T01:00:00-00:00", false);
+        scheduler.persist_to(StateFile::open(path));
+        scheduler.tick();
+        assert_eq!(backend.take_switches(), vec![true]);
+    }
+
+    fn force(on: bool, lasting: Lasting) -> Command {
+        Command::SetOverride(Some((on, lasting)))
+    }
+
+    #[test]
+    fn override_on_lasts_until_the_schedule_switches() {
+        let (commands, receiver) = channel();
+        let (mut scheduler, backend, clock) = setup("2021-01-04T18:00:00-00:00", false);
+        commands.send(force(true, Lasting::NextBoundary)).unwrap();
+        let switches = run_until(&mut scheduler, &backend, &clock, &receiver, "2021-01-04T22:30:00-00:00");
+        // nighttime agrees with it, so nothing changes but the schedule is back in charge
+        assert_eq!(switches, vec![at("Mon 18:00:00", true)]);
+        assert_eq!(scheduler.state().overridden, None);
+        let switches = run_until(&mut scheduler, &backend, &clock, &receiver, "2021-01-05T12:00:00-00:00");
+        assert_eq!(switches, vec![at("Tue 07:00:00", false)]);
+    }
+
+    #[test]
+    fn override_off_at_night_lasts_until_the_next_night() {
+        let (commands, receiver) = channel();
+        let (mut scheduler, backend, clock) = setup("2021-01-04T23:00:00-00:00", false);
+        scheduler.tick();
+        commands.send(force(false, Lasting::NextBoundary)).unwrap();
+        let switches = run_until(&mut scheduler, &backend, &clock, &receiver, "2021-01-06T00:00:00-00:00");
+        assert_eq!(switches, vec![
+            at("Mon 23:00:00", true), at("Mon 23:00:00", false),
+            at("Tue 22:00:00", true),
+        ]);
+    }
+
+    #[test]
+    fn override_until_a_time_runs_over_boundaries() {
+        let (commands, receiver) = channel();
+        let (mut scheduler, backend, clock) = setup("2021-01-04T21:00:00-00:00", false);
+        commands.send(force(false, Lasting::Until(NaiveTime::from_hms_opt(23, 0, 0).unwrap()))).unwrap();
+        let switches = run_until(&mut scheduler, &backend, &clock, &receiver, "2021-01-05T00:00:00-00:00");
+        assert_eq!(switches, vec![at("Mon 23:00:00", true)]);
+
+        // the last sleep went on until the morning
+        commands.send(force(true, Lasting::Minutes(90))).unwrap();
+        let switches = run_until(&mut scheduler, &backend, &clock, &receiver, "2021-01-05T12:00:00-00:00");
+        assert_eq!(switches, vec![at("Tue 07:00:00", false), at("Tue 07:00:00", true), at("Tue 08:30:00", false)]);
+    }
+
+    #[test]
+    fn override_forever_until_put_back_on_the_schedule() {
+        let (commands, receiver) = channel();
+        let (mut scheduler, backend, clock) = setup("2021-01-04T12:00:00-00:00", false);
+        commands.send(force(true, Lasting::Forever)).unwrap();
+        let switches = run_until(&mut scheduler, &backend, &clock, &receiver, "2021-01-07T12:00:00-00:00");
+        assert_eq!(switches, vec![at("Mon 12:00:00", true)]);
+        assert_eq!(scheduler.state().overridden, Some(Override { on: true, until: Expiry::Never, reason: Reason::Manual }));
+
+        commands.send(Command::SetOverride(None)).unwrap();
+        scheduler.step(&receiver);
+        assert_eq!(backend.take_switches(), vec![false]);
+        assert_eq!(scheduler.state().overridden, None);
+    }
+
+    #[test]
+    fn override_is_kept_through_a_reload() {
+        let (commands, receiver) = channel();
+        let (mut scheduler, backend, clock) = setup("2021-01-04T20:00:00-00:00", false);
+        commands.send(force(false, Lasting::Forever)).unwrap();
+        scheduler.step(&receiver);
+        let nighttime = WeeklySchedule::new(TimeRange::from_hmhm(20, 0, 7, 0).into(), Weekdays::default(), None);
+        commands.send(Command::Reload(Box::new(nighttime))).unwrap();
+        let switches = run_until(&mut scheduler, &backend, &clock, &receiver, "2021-01-05T12:00:00-00:00");
+        assert_eq!(switches, vec![]);
+    }
+
+    #[test]
+    fn override_runs_out_when_a_fade_starts() {
+        let (commands, receiver) = channel();
+        let (mut scheduler, backend, clock) = setup_fading("2021-01-04T12:00:30-00:00", RecordingBackend::fading(0.0));
+        commands.send(force(true, Lasting::NextBoundary)).unwrap();
+        scheduler.step(&receiver);
+        assert_eq!(backend.take_intensities(), vec![1.0]);
+        // fading in doesn't lighten what was forced on
+        let intensities = fade_until(&mut scheduler, &backend, &clock, "2021-01-04T21:31:00-00:00");
+        assert_eq!(intensities, vec![]);
+        assert_eq!(scheduler.state().overridden, None);
+
+        let (mut scheduler, backend, clock) = setup_fading("2021-01-04T12:00:30-00:00", RecordingBackend::fading(0.0));
+        commands.send(force(false, Lasting::NextBoundary)).unwrap();
+        scheduler.step(&receiver);
+        let intensities = fade_until(&mut scheduler, &backend, &clock, "2021-01-04T21:31:00-00:00");
+        assert_eq!(intensities.first().map(|(time, _)| time.as_str()), Some("21:30:30"));
+    }
+
+    #[test]
+    fn override_survives_a_restart() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("state.yaml");
+        let (commands, receiver) = channel();
+        let (mut scheduler, _, clock) = setup("2021-01-04T12:00:00-00:00", false);
+        scheduler.persist_to(StateFile::open(path.clone()));
+        commands.send(force(true, Lasting::Forever)).unwrap();
+        scheduler.step(&receiver);
+        drop(scheduler);
+
+        let restart = |clock: &FakeClock<Utc>| {
+            let backend = Arc::new(RecordingBackend::new(false));
+            let mut scheduler = Scheduler::new(night(), backend.clone(), clock.clone(), Duration::from_secs(0), DAY);
+            scheduler.persist_to(StateFile::open(path.clone()));
+            scheduler.tick();
+            (scheduler, backend.take_switches())
+        };
+        let (mut scheduler, switches) = restart(&clock);
+        assert_eq!(switches, vec![true]);
+        commands.send(force(true, Lasting::NextBoundary)).unwrap();
+        scheduler.step(&receiver);
+        drop(scheduler);
+
+        // one until the next boundary goes on if none went by while we weren't running
+        let (scheduler, switches) = restart(&clock);
+        assert_eq!(switches, vec![true]);
+        drop(scheduler);
+        clock.advance(OldDuration::hours(20));
+        let (_, switches) = restart(&clock);
+        assert_eq!(switches, Vec::<bool>::new());
+        let state = RuntimeState::load(&path);
+        assert_eq!(state.overridden, None);
+        assert_eq!(state.last_checked.unwrap().to_rfc3339(), "2021-01-05T08:00:00+00:00");
+    }
+
+    #[test]
+    fn describes_overrides() {
+        let until = DateTime::parse_from_rfc3339("2021-01-05T01:30:00+00:00").unwrap();
+        let forced = |on, until| Override { on, until, reason: Reason::Manual };
+        assert_eq!(forced(true, Expiry::NextBoundary).to_string(), "forced on until the schedule switches");
+        assert_eq!(forced(false, Expiry::At(until)).to_string(), "forced off until Tue 01:30");
+        assert_eq!(forced(true, Expiry::Never).to_string(), "forced on until it's put back on the schedule");
+        let snooze = Override { on: false, until: Expiry::At(until), reason: Reason::Snooze };
+        assert_eq!(snooze.to_string(), "snoozed until Tue 01:30");
+    }
+}
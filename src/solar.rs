@@ -0,0 +1,135 @@
+use serde::{Serialize, Deserialize, Deserializer};
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+use crate::config::number;
+
+/// where on earth we are, for working out when the sun rises and sets
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Location {
+    /// degrees north, south is negative
+    #[serde(deserialize_with = "latitude")]
+    pub latitude: f64,
+    /// degrees east, west is negative
+    #[serde(deserialize_with = "longitude")]
+    pub longitude: f64,
+}
+
+fn latitude<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    number(deserializer, -90.0..=90.0, "latitude has to be between -90 and 90 degrees")
+}
+
+fn longitude<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    number(deserializer, -180.0..=180.0, "longitude has to be between -180 and 180 degrees")
+}
+
+/// what the sun does on a given day
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sun {
+    Rises { sunrise: DateTime<Utc>, sunset: DateTime<Utc> },
+    /// the sun stays up all day
+    PolarDay,
+    /// the sun doesn't come up at all, it gets the least dark at `noon`
+    PolarNight { noon: DateTime<Utc> },
+}
+
+/// julian date of 2000-01-01 12:00 utc
+const J2000: f64 = 2_451_545.0;
+/// julian date of the unix epoch
+const UNIX_EPOCH: f64 = 2_440_587.5;
+
+/// work out sunrise and sunset on `date` at `location` with the sunrise equation,
+/// which is good to a minute or two and needs nothing but the date
+pub fn sun(date: NaiveDate, location: Location) -> Sun {
+    let days = (date - NaiveDate::from_ymd_opt(2000, 1, 1).unwrap()).num_days() as f64;
+    // mean solar noon, in days since j2000
+    let noon = days - location.longitude / 360.0;
+    let anomaly = (357.5291 + 0.985_600_28 * noon).rem_euclid(360.0).to_radians();
+    let center = 1.9148 * anomaly.sin() + 0.0200 * (2.0 * anomaly).sin() + 0.0003 * (3.0 * anomaly).sin();
+    let ecliptic_longitude = (anomaly.to_degrees() + center + 180.0 + 102.9372).rem_euclid(360.0).to_radians();
+    let transit = J2000 + noon + 0.0053 * anomaly.sin() - 0.0069 * (2.0 * ecliptic_longitude).sin();
+    let declination = (ecliptic_longitude.sin() * 23.4397_f64.to_radians().sin()).asin();
+
+    let latitude = location.latitude.to_radians();
+    // the sun is up when its upper edge is above the horizon, refraction included
+    let hour_angle = ((-0.833_f64).to_radians().sin() - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+    if hour_angle > 1.0 {
+        return Sun::PolarNight { noon: from_julian(transit) };
+    }
+    if hour_angle < -1.0 {
+        return Sun::PolarDay;
+    }
+    let half_day = hour_angle.acos().to_degrees() / 360.0;
+    Sun::Rises {
+        sunrise: from_julian(transit - half_day),
+        sunset: from_julian(transit + half_day),
+    }
+}
+
+fn from_julian(date: f64) -> DateTime<Utc> {
+    let seconds = ((date - UNIX_EPOCH) * 86400.0).round() as i64;
+    Utc.timestamp_opt(0, 0).unwrap() + Duration::seconds(seconds)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BERLIN: Location = Location { latitude: 52.52, longitude: 13.405 };
+    const TROMSO: Location = Location { latitude: 69.65, longitude: 18.96 };
+    const SANTIAGO: Location = Location { latitude: -33.45, longitude: -70.67 };
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn assert_close(time: DateTime<Utc>, expected: &str) {
+        let difference = (time - utc(expected)).num_seconds().abs();
+        assert!(difference <= 120, "{} is more than two minutes off {}", time, expected);
+    }
+
+    #[test]
+    fn midsummer_in_berlin() {
+        match sun(NaiveDate::from_ymd_opt(2021, 6, 21).unwrap(), BERLIN) {
+            Sun::Rises { sunrise, sunset } => {
+                assert_close(sunrise, "2021-06-21T02:43:00Z");
+                assert_close(sunset, "2021-06-21T19:33:00Z");
+            },
+            other => panic!("expected the sun to rise, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn midwinter_in_berlin() {
+        match sun(NaiveDate::from_ymd_opt(2021, 12, 21).unwrap(), BERLIN) {
+            Sun::Rises { sunrise, sunset } => {
+                assert_close(sunrise, "2021-12-21T07:15:00Z");
+                assert_close(sunset, "2021-12-21T14:54:00Z");
+            },
+            other => panic!("expected the sun to rise, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn southern_hemisphere_west_of_greenwich() {
+        match sun(NaiveDate::from_ymd_opt(2021, 6, 21).unwrap(), SANTIAGO) {
+            Sun::Rises { sunrise, sunset } => {
+                assert_close(sunrise, "2021-06-21T11:46:00Z");
+                assert_close(sunset, "2021-06-21T21:42:00Z");
+            },
+            other => panic!("expected the sun to rise, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn polar_day() {
+        assert_eq!(sun(NaiveDate::from_ymd_opt(2021, 6, 21).unwrap(), TROMSO), Sun::PolarDay);
+    }
+
+    #[test]
+    fn polar_night() {
+        match sun(NaiveDate::from_ymd_opt(2021, 12, 21).unwrap(), TROMSO) {
+            Sun::PolarNight { noon } => assert_close(noon, "2021-12-21T10:42:00Z"),
+            other => panic!("expected polar night, got {:?}", other),
+        }
+    }
+}
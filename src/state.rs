@@ -0,0 +1,158 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use chrono::{DateTime, Duration, FixedOffset};
+use serde::{Serialize, Deserialize};
+use log::warn;
+use crate::scheduler::Override;
+
+/// how often the check time alone is written, the scheduler checks a lot more often than that
+/// and it only has to be close enough to tell whether a boundary went by
+const CHECKED_EVERY_MINUTES: i64 = 10;
+
+/// what the app keeps between runs in the data dir, unlike the config it's ours to write
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RuntimeState {
+    /// when the scheduler last looked at the clock, to tell whether a boundary went by while we weren't running
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_checked: Option<DateTime<FixedOffset>>,
+    #[serde(rename = "override", default, skip_serializing_if = "Option::is_none")]
+    pub overridden: Option<Override>,
+    /// how the display was before we started, only there while it's ours
+    /// so a run that finds it knows the last one didn't get to put it back
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub was_grayscale: Option<bool>,
+}
+
+impl RuntimeState {
+    /// read what the last run left at `path`, there's nothing on the first run
+    /// and a file that can't be read is started over
+    pub fn load(path: &Path) -> Self {
+        let yaml = match fs::read_to_string(path) {
+            Ok(yaml) => yaml,
+            Err(_) => return Self::default(),
+        };
+        serde_yaml::from_str(&yaml).unwrap_or_else(|err| {
+            warn!("can't read the state from the last run, starting over: {}", err);
+            Self::default()
+        })
+    }
+
+    /// write it to `path` all at once, a crash halfway through leaves the last one there
+    pub fn store(&self, path: &Path) {
+        let written = path.parent().map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| replace(path, serde_yaml::to_string(self).unwrap_or_default().as_bytes()));
+        if let Err(err) = written {
+            warn!("can't save the state for the next run: {}", err);
+        }
+    }
+
+    /// check if `self` is only `stored` checked again a little later, which isn't worth writing yet
+    fn is_checked_again(&self, stored: &RuntimeState) -> bool {
+        let checked_again = match (stored.last_checked, self.last_checked) {
+            (Some(stored), Some(checked)) => checked >= stored && checked - stored < Duration::minutes(CHECKED_EVERY_MINUTES),
+            _ => false,
+        };
+        checked_again && Self { last_checked: stored.last_checked, ..self.clone() } == *stored
+    }
+}
+
+/// write `contents` next to `path` first and then move it over, moving is all or nothing
+fn replace(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let mut file = File::create(&temporary)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)
+}
+
+/// the state file, shared by everything that keeps something in it so nobody writes over the others
+#[derive(Clone)]
+pub struct StateFile {
+    path: PathBuf,
+    /// what's in it now, and what was last written
+    state: Arc<Mutex<(RuntimeState, RuntimeState)>>,
+}
+
+impl StateFile {
+    pub fn open(path: PathBuf) -> Self {
+        let state = RuntimeState::load(&path);
+        Self { path, state: Arc::new(Mutex::new((state.clone(), state))) }
+    }
+
+    pub fn get(&self) -> RuntimeState {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).0.clone()
+    }
+
+    /// change what's in the file, it's only written when something actually changed,
+    /// and when it's only been checked again once the last check written is a while ago
+    pub fn update<F: FnOnce(&mut RuntimeState)>(&self, change: F) {
+        let mut guard = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let (state, stored) = &mut *guard;
+        change(state);
+        if *state != *stored && !state.is_checked_again(stored) {
+            state.store(&self.path);
+            *stored = state.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keeps_what_everyone_put_in_it() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("data").join("state.yaml");
+        let state = StateFile::open(path.clone());
+        let other = state.clone();
+        state.update(|state| state.was_grayscale = Some(true));
+        let checked = DateTime::parse_from_rfc3339("2021-01-04T12:00:00+01:00").unwrap();
+        other.update(|state| state.last_checked = Some(checked));
+        let expected = RuntimeState { last_checked: Some(checked), overridden: None, was_grayscale: Some(true) };
+        assert_eq!(RuntimeState::load(&path), expected);
+        assert_eq!(StateFile::open(path).get(), expected);
+    }
+
+    #[test]
+    fn only_writes_the_check_time_now_and_then() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("state.yaml");
+        let state = StateFile::open(path.clone());
+        let checked = DateTime::parse_from_rfc3339("2021-01-04T12:00:00+01:00").unwrap();
+        let check = |minutes: i64| state.update(|state| state.last_checked = Some(checked + Duration::minutes(minutes)));
+        let written = || RuntimeState::load(&path).last_checked.map(|written| (written - checked).num_minutes());
+
+        check(0);
+        assert_eq!(written(), Some(0));
+        check(1);
+        assert_eq!(written(), Some(0));
+        assert_eq!(state.get().last_checked, Some(checked + Duration::minutes(1)));
+        check(CHECKED_EVERY_MINUTES);
+        assert_eq!(written(), Some(CHECKED_EVERY_MINUTES));
+        // the clock going back is written right away
+        check(2);
+        assert_eq!(written(), Some(2));
+        // and so is anything else, with the check that came with it
+        state.update(|state| {
+            state.last_checked = Some(checked + Duration::minutes(3));
+            state.was_grayscale = Some(false);
+        });
+        assert_eq!(written(), Some(3));
+        assert!(!path.with_extension("yaml.tmp").exists());
+    }
+
+    #[test]
+    fn starts_over_without_a_readable_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("state.yaml");
+        assert_eq!(RuntimeState::load(&path), RuntimeState::default());
+        fs::write(&path, "last_checked: [").unwrap();
+        assert_eq!(RuntimeState::load(&path), RuntimeState::default());
+    }
+}
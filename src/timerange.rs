@@ -1,19 +1,69 @@
-use std::fmt;
-use serde::{Serialize, Deserialize};
-use chrono::{NaiveTime, offset::TimeZone, DateTime, Duration};
-
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
-pub struct TimeRange {
-    start: NaiveTime,
-    end: NaiveTime,
+use std::{fmt, str::FromStr};
+use serde::{Serialize, Deserialize, Deserializer, de::{self, Visitor}};
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime, offset::TimeZone, DateTime, Duration};
+
+use crate::schedule::{Weekdays, WeeklySchedule};
+use crate::solar::{self, Location, Sun};
+
+/// start and end of something that happens every day, by default as times of day
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct TimeRange<T = NaiveTime> {
+    start: T,
+    end: T,
+}
+
+impl<T: Copy> TimeRange<T> {
+    #[allow(unused)]
+    pub fn new(start: T, end: T) -> Self {
+        Self { start, end }
+    }
+
+    pub fn start(self) -> T {
+        self.start
+    }
+
+    pub fn end(self) -> T {
+        self.end
+    }
+}
+
+impl TimeRange<Boundary> {
+    /// find out when the night after `date` starts and ends in local time,
+    /// if one of the boundaries doesn't happen because the sun doesn't set there's no night
+    ///
+    /// fixed times before noon and sunrise are on the morning after `date`,
+    /// the end is whenever the end boundary comes first after the start
+    pub fn on<Tz: TimeZone>(self, date: NaiveDate, tz: &Tz, location: Option<Location>) -> Option<(NaiveDateTime, NaiveDateTime)> {
+        let start_date = match self.start {
+            Boundary::At(time) if time < NaiveTime::from_hms_opt(12, 0, 0).unwrap() => date.succ_opt()?,
+            Boundary::At(_) | Boundary::Sunset(_) => date,
+            Boundary::Sunrise(_) => date.succ_opt()?,
+        };
+        let start = self.start.on(start_date, tz, location)?;
+        let end = (0..3)
+            .filter_map(|days| self.end.on(start.date() + Duration::days(days), tz, location))
+            .find(|end| end > &start)?;
+        Some((start, end))
+    }
+
+    /// the night after `date` as times of day
+    pub fn resolve<Tz: TimeZone>(self, date: NaiveDate, tz: &Tz, location: Option<Location>) -> Option<TimeRange> {
+        self.on(date, tz, location).map(|(start, end)| TimeRange { start: start.time(), end: end.time() })
+    }
+}
+
+impl From<TimeRange> for TimeRange<Boundary> {
+    fn from(range: TimeRange) -> Self {
+        Self { start: range.start.into(), end: range.end.into() }
+    }
 }
 
 impl TimeRange {
     /// create time range defined as start and end boundary in hours and minutes
     pub fn from_hmhm(start_h: u32, start_m: u32, end_h: u32, end_m: u32) -> Self {
         Self {
-            start: NaiveTime::from_hms(start_h, start_m, 0),
-            end: NaiveTime::from_hms(end_h, end_m, 0),
+            start: NaiveTime::from_hms_opt(start_h, start_m, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(end_h, end_m, 0).unwrap(),
         }
     }
 
@@ -38,21 +88,25 @@ impl TimeRange {
     #[allow(unused)]
     /// return time between given `time` and range boundary that would come sooner
     pub fn time_until_boundary_from(self, time: NaiveTime) -> NaiveTime {
-        NaiveTime::from_hms(0, 0, 0) + (self.next_boundary_from(time) - time)
+        NaiveTime::from_hms_opt(0, 0, 0).unwrap() + (self.next_boundary_from(time) - time)
     }
 
+    #[allow(unused)]
     /// return duration between given `time` and range boundary that would come sooner
     pub fn duration_until_boundary_from(self, time: NaiveTime) -> Duration {
         let mut dur = self.next_boundary_from(time) - time;
         // account for negative duration when boundary is on the next day
-        if dur < Duration::zero() { dur = dur + Duration::days(1) };
+        if dur < Duration::zero() { dur += Duration::days(1) };
         dur
     }
 
+    /// check if the range started or ended after `since` and up to `until`,
+    /// in absolute time so days with clock changes and timezone switches work out
+    #[allow(unused)]
     pub fn did_cross_boundary<Tz: TimeZone>(self, since: DateTime<Tz>, until: DateTime<Tz>) -> bool
         where <Tz as TimeZone>::Offset: Copy,
     {
-        (until - since) > self.duration_until_boundary_from(since.time())
+        WeeklySchedule::new(self.into(), Weekdays::default(), None).did_cross_boundary(since, until)
     }
 }
 
@@ -62,6 +116,168 @@ impl fmt::Display for TimeRange {
     }
 }
 
+impl fmt::Display for TimeRange<Boundary> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+/// the longest a boundary can be away from sunrise or sunset, so a night still
+/// starts within a day after the date it belongs to
+const MAX_OFFSET_HOURS: i64 = 12;
+
+/// start or end of a time range, either a fixed time of day or
+/// some time before or after the sun rises or sets
+///
+/// in the config it's a string like `"23:00:00"`, `"sunrise"` or `"sunset + 1h30m"`
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(into = "String")]
+pub enum Boundary {
+    At(NaiveTime),
+    Sunrise(Duration),
+    Sunset(Duration),
+}
+
+impl Boundary {
+    /// when this happens on `date` in local time, sunrise and sunset need a `location`
+    ///
+    /// in a polar night sunrise and sunset are when the sun gets closest to coming up,
+    /// in a polar day they don't happen at all
+    pub fn on<Tz: TimeZone>(self, date: NaiveDate, tz: &Tz, location: Option<Location>) -> Option<NaiveDateTime> {
+        let (sunrise, offset) = match self {
+            Self::At(time) => return Some(date.and_time(time)),
+            Self::Sunrise(offset) => (true, offset),
+            Self::Sunset(offset) => (false, offset),
+        };
+        let event = match solar::sun(date, location?) {
+            Sun::Rises { sunrise: time, .. } if sunrise => time,
+            Sun::Rises { sunset: time, .. } => time,
+            Sun::PolarNight { noon } => noon,
+            Sun::PolarDay => return None,
+        };
+        Some(event.with_timezone(tz).naive_local() + offset)
+    }
+
+    /// check if this depends on where the sun is
+    pub fn is_solar(self) -> bool {
+        !matches!(self, Self::At(_))
+    }
+}
+
+impl From<NaiveTime> for Boundary {
+    fn from(time: NaiveTime) -> Self {
+        Self::At(time)
+    }
+}
+
+impl FromStr for Boundary {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+        let (event, offset): (fn(Duration) -> Self, &str) = if let Some(offset) = s.strip_prefix("sunrise") {
+            (Self::Sunrise, offset)
+        } else if let Some(offset) = s.strip_prefix("sunset") {
+            (Self::Sunset, offset)
+        } else {
+            return NaiveTime::parse_from_str(&s, "%H:%M:%S")
+                .or_else(|_| NaiveTime::parse_from_str(&s, "%H:%M"))
+                .map(Self::At)
+                .map_err(|_| format!("expected a time like 23:00 or sunrise/sunset with an offset like sunset+1h30m, got {:?}", s));
+        };
+        let offset = parse_offset(offset).ok_or_else(|| format!("can't read the offset in {:?}, it should look like +2h or -1h30m", s))?;
+        if offset.num_minutes().abs() > MAX_OFFSET_HOURS * 60 {
+            return Err(format!("{:?} is more than {} hours from the sun", s, MAX_OFFSET_HOURS));
+        }
+        Ok(event(offset))
+    }
+}
+
+/// parse an offset like `+2h`, `- 30m` or `+1h30m`, nothing means no offset
+pub fn parse_offset(s: &str) -> Option<Duration> {
+    let s: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+    if s.is_empty() {
+        return Some(Duration::zero());
+    }
+    let (sign, mut rest) = if let Some(rest) = s.strip_prefix('+') {
+        (1, rest)
+    } else if let Some(rest) = s.strip_prefix('-') {
+        (-1, rest)
+    } else {
+        return None;
+    };
+    let mut offset = Duration::zero();
+    for (unit, minutes) in [('h', 60), ('m', 1)].iter() {
+        if let Some(index) = rest.find(*unit) {
+            // the sign only goes at the start, `parse` would take one in front of each number too
+            let number = &rest[..index];
+            if !number.bytes().all(|byte| byte.is_ascii_digit()) {
+                return None;
+            }
+            offset += Duration::minutes(number.parse::<i64>().ok()? * minutes);
+            rest = &rest[index + 1..];
+        }
+    }
+    if !rest.is_empty() || offset.is_zero() && s.len() == 1 {
+        return None;
+    }
+    Some(offset * sign)
+}
+
+/// parsed inside the visitor so that an error points at the boundary in the config
+impl<'de> Deserialize<'de> for Boundary {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct BoundaryVisitor;
+
+        impl<'de> Visitor<'de> for BoundaryVisitor {
+            type Value = Boundary;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a time of day, sunrise or sunset")
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<Boundary, E> {
+                s.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(BoundaryVisitor)
+    }
+}
+
+impl From<Boundary> for String {
+    fn from(boundary: Boundary) -> Self {
+        match boundary {
+            // the same as a plain `NaiveTime` so older configs look the same
+            Boundary::At(time) => time.format("%H:%M:%S").to_string(),
+            solar => solar.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Boundary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (event, offset) = match *self {
+            Self::At(time) => return write!(f, "{}", time.format("%H:%M")),
+            Self::Sunrise(offset) => ("sunrise", offset),
+            Self::Sunset(offset) => ("sunset", offset),
+        };
+        write!(f, "{}", event)?;
+        if offset.is_zero() {
+            return Ok(());
+        }
+        let minutes = offset.num_minutes().abs();
+        write!(f, "{}", if offset < Duration::zero() { '-' } else { '+' })?;
+        if minutes >= 60 {
+            write!(f, "{}h", minutes / 60)?;
+        }
+        if minutes % 60 != 0 {
+            write!(f, "{}m", minutes % 60)?;
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use chrono::Timelike;
@@ -71,29 +287,29 @@ mod test {
     #[test]
     fn same_day_1() {
         let nighttime = TimeRange::from_hmhm(1, 30, 10, 0);
-        let time = NaiveTime::from_hms(0, 0, 0);
-        assert_eq!(nighttime.includes(time), false);
-        assert_eq!(nighttime.time_until_boundary_from(time), NaiveTime::from_hms(1, 30, 0));
+        let time = NaiveTime::from_hms_opt(0, 0, 0).unwrap();
+        assert!(!nighttime.includes(time));
+        assert_eq!(nighttime.time_until_boundary_from(time), NaiveTime::from_hms_opt(1, 30, 0).unwrap());
         assert_eq!(nighttime.duration_until_boundary_from(time), Duration::minutes(90));
     }
 
     #[test]
     fn same_day_2() {
         let nighttime = TimeRange::from_hmhm(1, 30, 10, 0);
-        let time = NaiveTime::from_hms(3, 0, 0);
-        assert_eq!(nighttime.includes(time), true);
-        assert_eq!(nighttime.time_until_boundary_from(time), NaiveTime::from_hms(7, 0, 0));
+        let time = NaiveTime::from_hms_opt(3, 0, 0).unwrap();
+        assert!(nighttime.includes(time));
+        assert_eq!(nighttime.time_until_boundary_from(time), NaiveTime::from_hms_opt(7, 0, 0).unwrap());
         assert_eq!(nighttime.duration_until_boundary_from(time), Duration::hours(7));
     }
 
     #[test]
     fn same_day_3() {
         let nighttime = TimeRange::from_hmhm(1, 30, 10, 0);
-        let time = NaiveTime::from_hms(12, 0, 0);
-        assert_eq!(nighttime.includes(time), false);
-        assert_eq!(nighttime.time_until_boundary_from(time), NaiveTime::from_hms(13, 30, 0));
+        let time = NaiveTime::from_hms_opt(12, 0, 0).unwrap();
+        assert!(!nighttime.includes(time));
+        assert_eq!(nighttime.time_until_boundary_from(time), NaiveTime::from_hms_opt(13, 30, 0).unwrap());
         assert_eq!(
-            (NaiveTime::from_hms(0, 0, 0) + nighttime.duration_until_boundary_from(time)).num_seconds_from_midnight() / 60,
+            (NaiveTime::from_hms_opt(0, 0, 0).unwrap() + nighttime.duration_until_boundary_from(time)).num_seconds_from_midnight() / 60,
             30 + (13 * 60)
         );
     }
@@ -101,51 +317,51 @@ mod test {
     #[test]
     fn same_day_4() {
         let nighttime = TimeRange::from_hmhm(1, 30, 10, 0);
-        let time = NaiveTime::from_hms(18, 0, 0);
-        assert_eq!(nighttime.includes(time), false);
-        assert_eq!(nighttime.time_until_boundary_from(time), NaiveTime::from_hms(7, 30, 0));
+        let time = NaiveTime::from_hms_opt(18, 0, 0).unwrap();
+        assert!(!nighttime.includes(time));
+        assert_eq!(nighttime.time_until_boundary_from(time), NaiveTime::from_hms_opt(7, 30, 0).unwrap());
         assert_eq!(nighttime.duration_until_boundary_from(time), Duration::minutes(30 + (7 * 60)));
     }
 
     #[test]
     fn new_day_5() {
         let nighttime = TimeRange::from_hmhm(22, 0, 7, 0);
-        let time = NaiveTime::from_hms(18, 0, 0);
-        assert_eq!(nighttime.includes(time), false);
-        assert_eq!(nighttime.time_until_boundary_from(time), NaiveTime::from_hms(4, 0, 0));
+        let time = NaiveTime::from_hms_opt(18, 0, 0).unwrap();
+        assert!(!nighttime.includes(time));
+        assert_eq!(nighttime.time_until_boundary_from(time), NaiveTime::from_hms_opt(4, 0, 0).unwrap());
     }
 
     #[test]
     fn new_day_6() {
         let nighttime = TimeRange::from_hmhm(22, 0, 7, 0);
-        let time = NaiveTime::from_hms(23, 0, 0);
-        assert_eq!(nighttime.includes(time), true);
-        assert_eq!(nighttime.time_until_boundary_from(time), NaiveTime::from_hms(8, 0, 0));
+        let time = NaiveTime::from_hms_opt(23, 0, 0).unwrap();
+        assert!(nighttime.includes(time));
+        assert_eq!(nighttime.time_until_boundary_from(time), NaiveTime::from_hms_opt(8, 0, 0).unwrap());
     }
 
     #[test]
     fn new_day_7() {
         let nighttime = TimeRange::from_hmhm(22, 0, 7, 0);
-        let time = NaiveTime::from_hms(0, 0, 0);
-        assert_eq!(nighttime.includes(time), true);
-        assert_eq!(nighttime.time_until_boundary_from(time), NaiveTime::from_hms(7, 0, 0));
+        let time = NaiveTime::from_hms_opt(0, 0, 0).unwrap();
+        assert!(nighttime.includes(time));
+        assert_eq!(nighttime.time_until_boundary_from(time), NaiveTime::from_hms_opt(7, 0, 0).unwrap());
     }
 
 
     #[test]
     fn new_day_8() {
         let nighttime = TimeRange::from_hmhm(22, 0, 7, 0);
-        let time = NaiveTime::from_hms(6, 0, 0);
-        assert_eq!(nighttime.includes(time), true);
-        assert_eq!(nighttime.time_until_boundary_from(time), NaiveTime::from_hms(1, 0, 0));
+        let time = NaiveTime::from_hms_opt(6, 0, 0).unwrap();
+        assert!(nighttime.includes(time));
+        assert_eq!(nighttime.time_until_boundary_from(time), NaiveTime::from_hms_opt(1, 0, 0).unwrap());
     }
 
     #[test]
     fn new_day_9() {
         let nighttime = TimeRange::from_hmhm(22, 0, 7, 0);
-        let time = NaiveTime::from_hms(8, 0, 0);
-        assert_eq!(nighttime.includes(time), false);
-        assert_eq!(nighttime.time_until_boundary_from(time), NaiveTime::from_hms(14, 0, 0));
+        let time = NaiveTime::from_hms_opt(8, 0, 0).unwrap();
+        assert!(!nighttime.includes(time));
+        assert_eq!(nighttime.time_until_boundary_from(time), NaiveTime::from_hms_opt(14, 0, 0).unwrap());
     }
 
     #[test]
@@ -153,7 +369,7 @@ mod test {
         let nighttime = TimeRange::from_hmhm(22, 0, 7, 0);
         let since = DateTime::parse_from_rfc3339("2021-01-01T12:30:00-00:00").unwrap();
         let until = DateTime::parse_from_rfc3339("2021-01-01T12:31:00-00:00").unwrap();
-        assert_eq!(nighttime.did_cross_boundary(since, until), false);
+        assert!(!nighttime.did_cross_boundary(since, until));
     }
 
     #[test]
@@ -161,7 +377,7 @@ mod test {
         let nighttime = TimeRange::from_hmhm(22, 0, 7, 0);
         let since = DateTime::parse_from_rfc3339("2021-01-01T12:30:00-00:00").unwrap();
         let until = DateTime::parse_from_rfc3339("2021-01-01T23:30:00-00:00").unwrap();
-        assert_eq!(nighttime.did_cross_boundary(since, until), true);
+        assert!(nighttime.did_cross_boundary(since, until));
     }
 
     #[test]
@@ -169,7 +385,7 @@ mod test {
         let nighttime = TimeRange::from_hmhm(22, 0, 7, 0);
         let since = DateTime::parse_from_rfc3339("2021-01-01T12:30:00-00:00").unwrap();
         let until = DateTime::parse_from_rfc3339("2021-03-01T12:31:00-00:00").unwrap();
-        assert_eq!(nighttime.did_cross_boundary(since, until), true);
+        assert!(nighttime.did_cross_boundary(since, until));
     }
 
     #[test]
@@ -177,7 +393,7 @@ mod test {
         let nighttime = TimeRange::from_hmhm(22, 0, 7, 0);
         let since = DateTime::parse_from_rfc3339("2021-01-01T12:30:00-00:00").unwrap();
         let until = DateTime::parse_from_rfc3339("2021-03-01T12:31:00-00:00").unwrap();
-        assert_eq!(nighttime.did_cross_boundary(until, since), false);
+        assert!(!nighttime.did_cross_boundary(until, since));
     }
 
     #[test]
@@ -185,190 +401,254 @@ mod test {
         let nighttime = TimeRange::from_hmhm(0, 25, 0, 25);
         let since = DateTime::parse_from_rfc3339("2021-01-01T00:26:00-00:00").unwrap();
         let until = DateTime::parse_from_rfc3339("2021-01-01T00:26:05-00:00").unwrap();
-        assert_eq!(nighttime.did_cross_boundary(since, until), false);
+        assert!(!nighttime.did_cross_boundary(since, until));
     }
 
     #[test]
     fn boundary_same_minute_2() {
         let nighttime = TimeRange {
-            start: NaiveTime::from_hms(0, 25, 0),
-            end: NaiveTime::from_hms(0, 25, 10),
+            start: NaiveTime::from_hms_opt(0, 25, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(0, 25, 10).unwrap(),
         };
         let since = DateTime::parse_from_rfc3339("2021-01-01T00:26:00-00:00").unwrap();
         let until = DateTime::parse_from_rfc3339("2021-01-01T00:26:05-00:00").unwrap();
-        assert_eq!(nighttime.did_cross_boundary(since, until), false);
+        assert!(!nighttime.did_cross_boundary(since, until));
     }
 
     #[test]
     fn boundary_almost_same_minute_1() {
         let nighttime = TimeRange {
-            start: NaiveTime::from_hms(0, 25, 0),
-            end: NaiveTime::from_hms(0, 26, 0),
+            start: NaiveTime::from_hms_opt(0, 25, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(0, 26, 0).unwrap(),
         };
         let since = DateTime::parse_from_rfc3339("2021-01-01T00:25:59-00:00").unwrap();
         let until = DateTime::parse_from_rfc3339("2021-01-01T00:26:05-00:00").unwrap();
-        assert_eq!(nighttime.did_cross_boundary(since, until), true);
+        assert!(nighttime.did_cross_boundary(since, until));
     }
 
     #[test]
     fn boundary_almost_same_minute_2() {
         let nighttime = TimeRange {
-            start: NaiveTime::from_hms(0, 25, 0),
-            end: NaiveTime::from_hms(0, 26, 0),
+            start: NaiveTime::from_hms_opt(0, 25, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(0, 26, 0).unwrap(),
         };
         let since = DateTime::parse_from_rfc3339("2021-01-01T00:26:01-00:00").unwrap();
         let until = DateTime::parse_from_rfc3339("2021-01-01T00:26:05-00:00").unwrap();
-        assert_eq!(nighttime.did_cross_boundary(since, until), false);
+        assert!(!nighttime.did_cross_boundary(since, until));
     }
 
     #[test]
     fn boundary_almost_same_minute_3() {
         let nighttime = TimeRange {
-            start: NaiveTime::from_hms(0, 25, 0),
-            end: NaiveTime::from_hms(0, 26, 0),
+            start: NaiveTime::from_hms_opt(0, 25, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(0, 26, 0).unwrap(),
         };
         let since = DateTime::parse_from_rfc3339("2021-01-01T00:30:00-00:00").unwrap();
         let until = DateTime::parse_from_rfc3339("2021-01-01T00:30:05-00:00").unwrap();
-        assert_eq!(nighttime.did_cross_boundary(since, until), false);
+        assert!(!nighttime.did_cross_boundary(since, until));
     }
 
     #[test]
     fn boundary_almost_same_minute_4() {
         let nighttime = TimeRange {
-            start: NaiveTime::from_hms(0, 0, 0),
-            end: NaiveTime::from_hms(0, 26, 0),
+            start: NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(0, 26, 0).unwrap(),
         };
         let since = DateTime::parse_from_rfc3339("2021-01-01T00:30:00-00:00").unwrap();
         let until = DateTime::parse_from_rfc3339("2021-01-01T00:30:05-00:00").unwrap();
-        assert_eq!(nighttime.did_cross_boundary(since, until), false);
+        assert!(!nighttime.did_cross_boundary(since, until));
     }
 
     #[test]
     fn boundary_almost_same_minute_5() {
         let nighttime = TimeRange {
-            start: NaiveTime::from_hms(0, 0, 0),
-            end: NaiveTime::from_hms(0, 26, 0),
+            start: NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(0, 26, 0).unwrap(),
         };
         let since = DateTime::parse_from_rfc3339("2021-01-01T00:30:00-00:00").unwrap();
         let until = DateTime::parse_from_rfc3339("2021-01-01T04:30:05-00:00").unwrap();
-        assert_eq!(nighttime.did_cross_boundary(since, until), false);
+        assert!(!nighttime.did_cross_boundary(since, until));
     }
 
     #[test]
     fn boundary_almost_same_minute_6() {
         let nighttime = TimeRange {
-            start: NaiveTime::from_hms(0, 22, 0),
-            end: NaiveTime::from_hms(0, 26, 0),
+            start: NaiveTime::from_hms_opt(0, 22, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(0, 26, 0).unwrap(),
         };
         let since = DateTime::parse_from_rfc3339("2021-01-01T00:30:00-00:00").unwrap();
         let until = DateTime::parse_from_rfc3339("2021-01-01T08:30:05-00:00").unwrap();
-        assert_eq!(nighttime.did_cross_boundary(since, until), false);
+        assert!(!nighttime.did_cross_boundary(since, until));
     }
 
     #[test]
     fn boundary_quite_a_different_minute_1() {
         let nighttime = TimeRange {
-            start: NaiveTime::from_hms(22, 0, 0),
-            end: NaiveTime::from_hms(0, 26, 0),
+            start: NaiveTime::from_hms_opt(22, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(0, 26, 0).unwrap(),
         };
         let since = DateTime::parse_from_rfc3339("2021-01-01T00:30:00-00:00").unwrap();
         let until = DateTime::parse_from_rfc3339("2021-01-01T08:30:05-00:00").unwrap();
-        assert_eq!(nighttime.did_cross_boundary(since, until), false);
+        assert!(!nighttime.did_cross_boundary(since, until));
     }
 
     #[test]
     fn boundary_within_hour_different_days() {
         let nighttime = TimeRange {
-            start: NaiveTime::from_hms(23, 26, 0),
-            end: NaiveTime::from_hms(0, 26, 0),
+            start: NaiveTime::from_hms_opt(23, 26, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(0, 26, 0).unwrap(),
         };
         let since = DateTime::parse_from_rfc3339("2021-01-01T00:30:00-00:00").unwrap();
         let until = DateTime::parse_from_rfc3339("2021-01-01T08:30:05-00:00").unwrap();
-        assert_eq!(nighttime.did_cross_boundary(since, until), false);
+        assert!(!nighttime.did_cross_boundary(since, until));
     }
 
     #[test]
     fn boundary_within_halfhour_different_days() {
         let nighttime = TimeRange {
-            start: NaiveTime::from_hms(23, 56, 0),
-            end: NaiveTime::from_hms(0, 26, 0),
+            start: NaiveTime::from_hms_opt(23, 56, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(0, 26, 0).unwrap(),
         };
         let since = DateTime::parse_from_rfc3339("2021-01-01T00:30:00-00:00").unwrap();
         let until = DateTime::parse_from_rfc3339("2021-01-01T08:30:05-00:00").unwrap();
-        assert_eq!(nighttime.did_cross_boundary(since, until), false);
+        assert!(!nighttime.did_cross_boundary(since, until));
     }
 
     #[test]
     fn boundary_within_20_minutes_same_day() {
         let nighttime = TimeRange {
-            start: NaiveTime::from_hms(0, 6, 0),
-            end: NaiveTime::from_hms(0, 26, 0),
+            start: NaiveTime::from_hms_opt(0, 6, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(0, 26, 0).unwrap(),
         };
         let since = DateTime::parse_from_rfc3339("2021-01-01T00:30:00-00:00").unwrap();
         let until = DateTime::parse_from_rfc3339("2021-01-01T08:30:05-00:00").unwrap();
-        assert_eq!(nighttime.did_cross_boundary(since, until), false);
+        assert!(!nighttime.did_cross_boundary(since, until));
     }
 
     #[test]
     fn boundary_within_25_minutes_same_day() {
         let nighttime = TimeRange {
-            start: NaiveTime::from_hms(0, 1, 0),
-            end: NaiveTime::from_hms(0, 26, 0),
+            start: NaiveTime::from_hms_opt(0, 1, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(0, 26, 0).unwrap(),
         };
         let since = DateTime::parse_from_rfc3339("2021-01-01T00:30:00-00:00").unwrap();
         let until = DateTime::parse_from_rfc3339("2021-01-01T08:30:05-00:00").unwrap();
-        assert_eq!(nighttime.did_cross_boundary(since, until), false);
+        assert!(!nighttime.did_cross_boundary(since, until));
     }
 
     #[test]
     fn boundary_within_27_minutes_different_days() {
         let nighttime = TimeRange {
-            start: NaiveTime::from_hms(23, 59, 0),
-            end: NaiveTime::from_hms(0, 26, 0),
+            start: NaiveTime::from_hms_opt(23, 59, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(0, 26, 0).unwrap(),
         };
         let since = DateTime::parse_from_rfc3339("2021-01-01T00:30:00-00:00").unwrap();
         let until = DateTime::parse_from_rfc3339("2021-01-01T08:30:05-00:00").unwrap();
-        assert_eq!(nighttime.did_cross_boundary(since, until), false);
+        assert!(!nighttime.did_cross_boundary(since, until));
     }
 
     #[test]
     fn boundary_within_27_minutes_same_day() {
         let nighttime = TimeRange {
-            start: NaiveTime::from_hms(0, 0, 0),
-            end: NaiveTime::from_hms(0, 27, 0),
+            start: NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(0, 27, 0).unwrap(),
         };
         let since = DateTime::parse_from_rfc3339("2021-01-01T00:30:00-00:00").unwrap();
         let until = DateTime::parse_from_rfc3339("2021-01-01T08:30:05-00:00").unwrap();
-        assert_eq!(nighttime.did_cross_boundary(since, until), false);
+        assert!(!nighttime.did_cross_boundary(since, until));
     }
 
     #[test]
     fn boundary_within_27_minutes_same_day_alt() {
         let nighttime = TimeRange {
-            start: NaiveTime::from_hms(0, 0, 0),
-            end: NaiveTime::from_hms(0, 27, 0),
+            start: NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(0, 27, 0).unwrap(),
         };
         let since = DateTime::parse_from_rfc3339("2021-01-01T04:30:00-00:00").unwrap();
         let until = DateTime::parse_from_rfc3339("2021-01-01T10:30:05-00:00").unwrap();
-        assert_eq!(nighttime.did_cross_boundary(since, until), false);
+        assert!(!nighttime.did_cross_boundary(since, until));
     }
 
     #[test]
     fn boundary_within_4_hours_same_day() {
         let nighttime = TimeRange {
-            start: NaiveTime::from_hms(0, 0, 0),
-            end: NaiveTime::from_hms(4, 0, 0),
+            start: NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(4, 0, 0).unwrap(),
         };
         let since = DateTime::parse_from_rfc3339("2021-01-01T04:30:00-00:00").unwrap();
         let until = DateTime::parse_from_rfc3339("2021-01-01T10:30:05-00:00").unwrap();
-        assert_eq!(nighttime.did_cross_boundary(since, until), false);
+        assert!(!nighttime.did_cross_boundary(since, until));
     }
 
     #[test]
     fn boundary_new_day_1() {
         let nighttime = TimeRange::from_hmhm(22, 0, 7, 0);
-        let time = NaiveTime::from_hms(18, 0, 0);
-        assert_eq!(nighttime.includes(time), false);
-        assert_eq!(nighttime.time_until_boundary_from(time), NaiveTime::from_hms(4, 0, 0));
+        let time = NaiveTime::from_hms_opt(18, 0, 0).unwrap();
+        assert!(!nighttime.includes(time));
+        assert_eq!(nighttime.time_until_boundary_from(time), NaiveTime::from_hms_opt(4, 0, 0).unwrap());
+    }
+
+    fn parse(s: &str) -> Boundary {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_boundaries() {
+        assert_eq!(parse("23:00:00"), Boundary::At(NaiveTime::from_hms_opt(23, 0, 0).unwrap()));
+        assert_eq!(parse("7:30"), Boundary::At(NaiveTime::from_hms_opt(7, 30, 0).unwrap()));
+        assert_eq!(parse("sunrise"), Boundary::Sunrise(Duration::zero()));
+        assert_eq!(parse("Sunset + 2h"), Boundary::Sunset(Duration::hours(2)));
+        assert_eq!(parse("sunset-1h30m"), Boundary::Sunset(Duration::minutes(-90)));
+        assert_eq!(parse("sunrise +45m"), Boundary::Sunrise(Duration::minutes(45)));
+    }
+
+    #[test]
+    fn parse_bad_boundaries() {
+        for bad in &["25:00", "noon", "sunset+", "sunset+2", "sunset 2h", "sunrise+30m1h", "sunset+13h", "sunset+1h-30m", "sunrise-1h+30m"] {
+            assert!(bad.parse::<Boundary>().is_err(), "{} should not parse", bad);
+        }
+    }
+
+    #[test]
+    fn parse_offsets_that_arent_ascii() {
+        // a typographic minus sign, e.g. pasted from somewhere, is more than one byte
+        assert_eq!(parse_offset("−1h"), None);
+        assert_eq!(parse_offset("１h"), None);
+        assert_eq!(parse_offset("+1ｈ"), None);
+        assert!("sunset −1h".parse::<Boundary>().is_err());
+    }
+
+    #[test]
+    fn display_boundaries() {
+        for s in &["23:00", "sunrise", "sunset+2h", "sunset-1h30m", "sunrise+45m"] {
+            assert_eq!(parse(s).to_string(), *s);
+        }
+        assert_eq!(String::from(parse("23:00")), "23:00:00");
+    }
+
+    const BERLIN: Location = Location { latitude: 52.52, longitude: 13.405 };
+
+    #[test]
+    fn sunset_to_sunrise() {
+        let nighttime = TimeRange::new(parse("sunset+2h"), parse("sunrise"));
+        let date = NaiveDate::from_ymd_opt(2021, 12, 21).unwrap();
+        let (start, end) = nighttime.on(date, &chrono::Utc, Some(BERLIN)).unwrap();
+        assert_eq!(start.format("%d %H:%M").to_string(), "21 16:53");
+        assert_eq!(end.format("%d %H:%M").to_string(), "22 07:15");
+        assert_eq!(nighttime.resolve(date, &chrono::Utc, Some(BERLIN)).unwrap().to_string(), "16:53-07:15");
+    }
+
+    #[test]
+    fn fixed_start_until_sunrise() {
+        let nighttime = TimeRange::new(parse("01:00"), parse("sunrise"));
+        let (start, end) = nighttime.on(NaiveDate::from_ymd_opt(2021, 12, 21).unwrap(), &chrono::Utc, Some(BERLIN)).unwrap();
+        assert_eq!(start.format("%d %H:%M").to_string(), "22 01:00");
+        assert_eq!(end.format("%d %H:%M").to_string(), "22 07:15");
+    }
+
+    #[test]
+    fn sun_needs_a_location() {
+        let nighttime = TimeRange::new(parse("sunset"), parse("07:00"));
+        assert_eq!(nighttime.on(NaiveDate::from_ymd_opt(2021, 12, 21).unwrap(), &chrono::Utc, None), None);
     }
 }
@@ -1,53 +1,292 @@
 use std::{
+    ffi::c_void,
     path::PathBuf,
-    process::Command,
+    process::{self, Command},
+    sync::{Arc, Mutex, mpsc::{self, Sender}},
+    time::Duration,
+};
+use chrono::Local;
+use cocoa::{
+    appkit::{NSApp, NSApplication, NSMenu, NSMenuItem, NSStatusBar},
+    base::{id, nil, BOOL, NO, YES},
+    foundation::{NSAutoreleasePool, NSString},
 };
-use cocoa::base::id;
 use objc::runtime::{Object, Sel};
-use tray_item::TrayItem;
 
 use crate::config::Config;
-use crate::grayscale::{is_grayscale, set_grayscale};
-
-pub fn start_tray(config_path: PathBuf, config: &Config, was_grayscale: bool) {
-    // 😴🌚☾☀︎
-    let mut tray = TrayItem::new(&config.title, "").unwrap();
-    tray.add_label(&format!("✨GRAY SCREEN FOR GAY BABES {}✨", &config.nighttime)).unwrap();
-    #[cfg(debug_assertions)]
-    tray.add_label(&format!("debug mode")).unwrap();
-
-    tray.add_menu_item("edit settings - needs restart", move || {
-        Command::new("open")
-            .arg(&config_path)
-            .output()
-            .expect("failed to open config file in system default application");
-    }).unwrap();
-
-    let inner = tray.inner_mut();    
-    // revert to the original grayscale setting when quitting the app
-    extern fn on_app_should_terminate(this: &mut Object, _cmd: Sel, _notification: id) {
-        let was_grayscale: bool = unsafe { *(this.get_ivar("was_grayscale")) };
-        set_grayscale(was_grayscale);
+use crate::backend::DisplayBackend;
+use crate::restore::RestoreGuard;
+use crate::schedule::WeeklySchedule;
+use crate::scheduler::{self, Lasting, Snooze};
+
+/// makes the status item as wide as its title
+const VARIABLE_LENGTH: f64 = -1.0;
+
+/// the menu bar item, it's built on cocoa directly so that its title
+/// and label can change while the app is running
+pub struct Tray {
+    status_item: MainThread,
+    label: MainThread,
+    delegate: MainThread,
+    /// what the label shows tonight's ranges of, it's worked out again whenever the menu opens
+    schedule: Arc<Mutex<WeeklySchedule>>,
+}
+
+/// an appkit object that other threads can only ask the main thread to do something with
+#[derive(Clone, Copy)]
+struct MainThread(id);
+
+// appkit objects may only be used from the main thread, but `performSelectorOnMainThread:`
+// is fine from any thread and it's all `MainThread` does with them. they're never released,
+// the menu holds on to its items and the app to its delegate until it quits
+unsafe impl Send for MainThread {}
+unsafe impl Sync for MainThread {}
+
+impl MainThread {
+    /// send `selector` with `argument` on the main thread and wait for it
+    fn perform(self, selector: Sel, argument: id) {
+        unsafe {
+            let () = msg_send![self.0, performSelectorOnMainThread: selector withObject: argument waitUntilDone: YES];
+        }
+    }
+}
+
+/// what the menu items need, it lives as long as the app
+struct Delegate {
+    backend: Arc<dyn DisplayBackend>,
+    guard: Arc<RestoreGuard>,
+    commands: Sender<scheduler::Command>,
+    config_path: PathBuf,
+    schedule: Arc<Mutex<WeeklySchedule>>,
+}
+
+/// what the app delegate `this` was set up with
+fn delegate_state(this: &Object) -> &'static Delegate {
+    unsafe { &*(*this.get_ivar::<*mut c_void>("state") as *const Delegate) }
+}
+
+impl Tray {
+    /// set up the tray for `config`, it shows up once `run` is called
+    pub fn new(
+        config_path: PathBuf,
+        config: &Config,
+        backend: Arc<dyn DisplayBackend>,
+        guard: Arc<RestoreGuard>,
+        commands: Sender<scheduler::Command>,
+    ) -> Self {
+        unsafe {
+            let _pool = NSAutoreleasePool::new(nil);
+            let app = NSApp();
+
+            // revert to the original grayscale setting when quitting the app
+            extern fn on_app_should_terminate(this: &mut Object, _cmd: Sel, _notification: id) {
+                delegate_state(this).guard.restore();
+            }
+            extern fn edit_settings(this: &mut Object, _cmd: Sel, _sender: id) {
+                Command::new("open")
+                    .arg(&delegate_state(this).config_path)
+                    .output()
+                    .expect("failed to open config file in system default application");
+            }
+            // only shown while the config file has an error in it
+            extern fn show_error(this: &mut Object, _cmd: Sel, message: id) {
+                unsafe {
+                    let item: id = *this.get_ivar("error_item");
+                    let hidden: BOOL = if message == nil { YES } else { NO };
+                    let () = msg_send![item, setHidden: hidden];
+                    if message != nil {
+                        let () = msg_send![item, setTitle: message];
+                    }
+                }
+            }
+            // the scheduler keeps it like this until the next boundary, and when quitting
+            extern fn toggle_grayscale(this: &mut Object, _cmd: Sel, _sender: id) {
+                let should_be_set_to_grayscale = !delegate_state(this).backend.is_grayscale();
+                send(this, scheduler::Command::SetOverride(Some((should_be_set_to_grayscale, Lasting::NextBoundary))));
+            }
+            extern fn back_to_schedule(this: &mut Object, _cmd: Sel, _sender: id) {
+                send(this, scheduler::Command::SetOverride(None));
+            }
+            // ask the scheduler whether the effect is forced on or off every time the menu opens,
+            // and work out tonight again since sunset moves and the app might have been running for days
+            extern fn menu_will_open(this: &mut Object, _cmd: Sel, _menu: id) {
+                let (title, switch) = {
+                    let schedule = delegate_state(this).schedule.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                    (label(&schedule), schedule.next_boundary_after(&Local::now()))
+                };
+                unsafe {
+                    let item: id = *this.get_ivar("label_item");
+                    let title = NSString::alloc(nil).init_str(&title).autorelease();
+                    let () = msg_send![item, setTitle: title];
+                    let item: id = *this.get_ivar("snooze_until_item");
+                    let hidden: BOOL = if switch.is_none() { YES } else { NO };
+                    let () = msg_send![item, setHidden: hidden];
+                    if let Some(switch) = switch {
+                        let title = NSString::alloc(nil).init_str(&format!("snooze until {}", switch.format("%H:%M"))).autorelease();
+                        let () = msg_send![item, setTitle: title];
+                    }
+                }
+                let (reply, state) = mpsc::channel();
+                send(this, scheduler::Command::GetState(reply));
+                let overridden = state.recv_timeout(Duration::from_millis(200)).ok().and_then(|state| state.overridden);
+                unsafe {
+                    let item: id = *this.get_ivar("override_item");
+                    let hidden: BOOL = if overridden.is_none() { YES } else { NO };
+                    let () = msg_send![item, setHidden: hidden];
+                    if let Some(overridden) = overridden {
+                        let title = NSString::alloc(nil).init_str(&format!("{}, back to the schedule", overridden)).autorelease();
+                        let () = msg_send![item, setTitle: title];
+                    }
+                }
+            }
+
+            fn send(this: &mut Object, command: scheduler::Command) {
+                delegate_state(this).commands.send(command).ok();
+            }
+            fn snooze(this: &mut Object, minutes: u64) {
+                send(this, scheduler::Command::Snooze(Snooze::For(Duration::from_secs(minutes * 60))));
+            }
+            extern fn snooze_quarter_hour(this: &mut Object, _cmd: Sel, _sender: id) {
+                snooze(this, 15);
+            }
+            extern fn snooze_hour(this: &mut Object, _cmd: Sel, _sender: id) {
+                snooze(this, 60);
+            }
+            // like `goodnight snooze 07:00`, until the schedule switches next
+            extern fn snooze_until_switch(this: &mut Object, _cmd: Sel, _sender: id) {
+                let switch = delegate_state(this).schedule.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .next_boundary_after(&Local::now());
+                if let Some(switch) = switch {
+                    send(this, scheduler::Command::Snooze(Snooze::Until(switch.time())));
+                }
+            }
+
+            let menu = NSMenu::new(nil);
+            let error_item = add_item(menu, "", None, nil);
+            let () = msg_send![error_item, setHidden: YES];
+            let schedule = Arc::new(Mutex::new(config.schedule()));
+            let label = add_item(menu, &label(&config.schedule()), None, nil);
+            let override_item = add_item(menu, "", None, nil);
+            let () = msg_send![override_item, setHidden: YES];
+            // it goes with the other snoozes further down, its title is set when the menu opens
+            let snooze_until_item = NSMenuItem::new(nil).autorelease();
+
+            // the delegate lives as long as the app, and quitting exits the process
+            // from inside appkit, so there's never a point to free its state at
+            let state: &'static Delegate = Box::leak(Box::new(Delegate {
+                backend,
+                guard,
+                commands,
+                config_path,
+                schedule: Arc::clone(&schedule),
+            }));
+            let state = state as *const Delegate as *mut c_void;
+            let delegate = delegate!("AppDelegate", {
+                state: *mut c_void = state,
+                label_item: id = label,
+                error_item: id = error_item,
+                override_item: id = override_item,
+                snooze_until_item: id = snooze_until_item,
+                (applicationWillTerminate:) => on_app_should_terminate as extern fn(&mut Object, Sel, id),
+                (editSettings:) => edit_settings as extern fn(&mut Object, Sel, id),
+                (toggleGrayscale:) => toggle_grayscale as extern fn(&mut Object, Sel, id),
+                (showError:) => show_error as extern fn(&mut Object, Sel, id),
+                (snoozeQuarterHour:) => snooze_quarter_hour as extern fn(&mut Object, Sel, id),
+                (snoozeHour:) => snooze_hour as extern fn(&mut Object, Sel, id),
+                (snoozeUntilSwitch:) => snooze_until_switch as extern fn(&mut Object, Sel, id),
+                (backToSchedule:) => back_to_schedule as extern fn(&mut Object, Sel, id),
+                (menuWillOpen:) => menu_will_open as extern fn(&mut Object, Sel, id)
+            });
+            let () = msg_send![app, setDelegate: delegate];
+            let () = msg_send![menu, setDelegate: delegate];
+            let () = msg_send![override_item, setAction: sel!(backToSchedule:)];
+            let () = msg_send![override_item, setTarget: delegate];
+
+            #[cfg(debug_assertions)]
+            add_item(menu, "debug mode", None, nil);
+            add_item(menu, "edit settings", Some(sel!(editSettings:)), delegate);
+            add_item(menu, "toggle grayscale", Some(sel!(toggleGrayscale:)), delegate);
+            add_item(menu, "snooze 15 minutes", Some(sel!(snoozeQuarterHour:)), delegate);
+            add_item(menu, "snooze 1 hour", Some(sel!(snoozeHour:)), delegate);
+            let () = msg_send![snooze_until_item, setAction: sel!(snoozeUntilSwitch:)];
+            let () = msg_send![snooze_until_item, setTarget: delegate];
+            menu.addItem_(snooze_until_item);
+            add_item(menu, "quit", Some(sel!(terminate:)), app);
+
+            // 😴🌚☾☀︎
+            let status_item = NSStatusBar::systemStatusBar(nil).statusItemWithLength_(VARIABLE_LENGTH);
+            // the status bar only keeps the item around while someone else holds on to it
+            let () = msg_send![status_item, retain];
+            let () = msg_send![status_item, setMenu: menu];
+            let tray = Self {
+                status_item: MainThread(status_item),
+                label: MainThread(label),
+                delegate: MainThread(delegate),
+                schedule,
+            };
+            tray.update(config);
+            tray
+        }
+    }
+
+    /// show what's in `config`, from any thread
+    pub fn update(&self, config: &Config) {
+        let schedule = config.schedule();
+        set_title(self.status_item, &config.title);
+        set_title(self.label, &label(&schedule));
+        *self.schedule.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = schedule;
+    }
+
+    /// show what's wrong with the config file at the top of the menu, or nothing for `None`
+    pub fn set_error(&self, error: Option<&str>) {
+        unsafe {
+            let message = match error {
+                Some(error) => NSString::alloc(nil).init_str(&format!("⚠️ config file: {}", error)),
+                None => nil,
+            };
+            self.delegate.perform(sel!(showError:), message);
+            if message != nil {
+                let () = msg_send![message, release];
+            }
+        }
+    }
+}
+
+/// show the tray and run the app, quitting exits the process from inside
+/// `terminate:` right after `applicationWillTerminate:` put the display back
+pub fn run() -> ! {
+    unsafe {
+        let app = NSApp();
+        app.activateIgnoringOtherApps_(YES);
+        app.run();
+    }
+    // only `stop:` makes it return, and nothing sends that
+    process::exit(0)
+}
+
+fn label(schedule: &WeeklySchedule) -> String {
+    let tonight = schedule.tonight(&Local::now());
+    format!("✨GRAY SCREEN FOR GAY BABES {}✨", tonight)
+}
+
+/// add an item to the end of `menu`, without an action it's a label
+unsafe fn add_item(menu: id, title: &str, action: Option<Sel>, target: id) -> id {
+    let title = NSString::alloc(nil).init_str(title).autorelease();
+    let item = NSMenuItem::new(nil).autorelease();
+    let () = msg_send![item, setTitle: title];
+    if let Some(action) = action {
+        let () = msg_send![item, setAction: action];
+        let () = msg_send![item, setTarget: target];
+    }
+    menu.addItem_(item);
+    item
+}
+
+/// appkit may only be used from the main thread, so hand the change over to it
+fn set_title(item: MainThread, title: &str) {
+    unsafe {
+        let title = NSString::alloc(nil).init_str(title);
+        item.perform(sel!(setTitle:), title);
+        let () = msg_send![title, release];
     }
-    let delegate = unsafe {
-        delegate!("AppDelegate", {
-            was_grayscale: bool = was_grayscale,
-            (applicationWillTerminate:) => on_app_should_terminate as extern fn(&mut Object, Sel, id)
-        })
-    };
-    unsafe {inner.set_app_delegate(delegate);};
-
-    // create a mutable reference from the raw pointer for capturing with closure
-    // (raw pointers can't implement sync and send)
-    let delegate_ref = unsafe {&mut*delegate};
-    inner.add_menu_item("toggle grayscale", move || {
-        let should_be_set_to_grayscale = !is_grayscale();
-        set_grayscale(should_be_set_to_grayscale);
-        // keep track of manual toggles to avoid overriding them with initial value when quitting
-        unsafe {delegate_ref.set_ivar::<bool>("was_grayscale", should_be_set_to_grayscale)};
-    }).unwrap();
-    
-    inner.add_quit_item("quit");
-
-    inner.display();
 }